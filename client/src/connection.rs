@@ -1,96 +1,631 @@
-use std::net::TcpStream;
-
-use crate::{
-    ball::{add_ball, move_ball, remove_ball, BallQuery},
-    hoop::{add_hoop, move_hoop, HoopQuery},
-};
-use bevy::prelude::*;
-use clap::Parser;
-use nope_the_hoop_proto::{
-    message::{ToClientMessage, ToServerMessage},
-    state::{GameState, UpdateState},
-    sync::MessageStream,
-};
-
-use crate::{Args, AssetHandles, CurrentRole, HandleErrors, Role};
-
-#[derive(Resource)]
-pub struct ServerConnection(MessageStream<TcpStream>);
-
-impl ServerConnection {
-    pub fn send(&mut self, message: ToServerMessage) {
-        self.0.write_message(&message).handle();
-    }
-}
-
-pub fn setup(app: &mut App) {
-    app.add_systems(Startup, setup_connect)
-        .add_systems(Update, update_from_server);
-}
-
-fn setup_connect(mut commands: Commands) {
-    let args = Args::parse();
-    info!("Connecting to {}:{}", args.server, args.port);
-    let stream = establish_connection(&args).handle();
-    let mut connection = ServerConnection(stream);
-    send_hello(&mut connection);
-    commands.insert_resource(connection);
-    info!("Connected");
-}
-
-fn update_from_server(
-    mut commands: Commands,
-    mut server: ResMut<ServerConnection>,
-    mut current_role: ResMut<CurrentRole>,
-    asset_handles: Res<AssetHandles>,
-    mut hoops_and_balls: ParamSet<(HoopQuery, BallQuery)>,
-) {
-    let messages = server.0.read_messages::<ToClientMessage>().handle();
-    for message in messages {
-        match message {
-            ToClientMessage::EstablishAsHoop => {
-                trace!("I'm a hoop");
-                current_role.0 = Role::Hoop;
-            }
-            ToClientMessage::EstablishAsBall { id } => {
-                trace!("I'm a ball");
-                current_role.0 = Role::Ball { id };
-            }
-            ToClientMessage::UpdateState(UpdateState::MoveHoop { x }) => {
-                move_hoop(&mut hoops_and_balls.p0(), x);
-            }
-            ToClientMessage::UpdateState(UpdateState::AddBall { id, position }) => {
-                add_ball(&mut commands, id, position, &asset_handles.ball_assets);
-            }
-            ToClientMessage::UpdateState(UpdateState::RemoveBall { id }) => {
-                remove_ball(&mut commands, id, &mut hoops_and_balls.p1());
-            }
-            ToClientMessage::UpdateState(UpdateState::MoveBall { id, position }) => {
-                move_ball(id, position, &mut hoops_and_balls.p1());
-            }
-            ToClientMessage::InitialState(GameState {
-                hoop_x,
-                ball_positions,
-            }) => {
-                add_hoop(&mut commands, hoop_x, &asset_handles.hoop_assets);
-                for (id, ball) in ball_positions {
-                    add_ball(&mut commands, id, ball, &asset_handles.ball_assets);
-                }
-            }
-            ToClientMessage::EstablishAsObserver => {
-                trace!("I'm an observer");
-                current_role.0 = Role::Observer;
-            }
-        }
-    }
-}
-
-fn establish_connection(args: &Args) -> anyhow::Result<MessageStream<TcpStream>> {
-    let stream = TcpStream::connect((args.server.as_str(), args.port))?;
-    stream.set_nonblocking(true)?;
-    Ok(MessageStream::new(stream))
-}
-
-fn send_hello(server: &mut ServerConnection) {
-    server.send(ToServerMessage::Hello { game_id: 123 });
-}
+use std::{
+    collections::VecDeque,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    ball::{add_ball, remove_ball, reset_ball, shot_rejected, spawn_ball, Ball, BallQuery},
+    hoop::{add_hoop, move_hoop, Hoop, HoopPrediction, HoopQuery},
+    hud::MatchHud,
+    interpolation::Interpolation,
+    lobby::Lobby,
+    swap::RoleSwaps,
+};
+use bevy::prelude::*;
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use nope_the_hoop_proto::{
+    auth::{GamePassword, Nonce, PassphraseHash, Salt, SessionToken},
+    lobby::{GameRef, LobbyError},
+    message::{
+        CommandError, PreferredRole, ToClientMessage, ToServerMessage, CAPABILITIES,
+        PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    state::{GameState, StateDelta, UpdateState},
+    sync::MessageStream,
+    tls::{self, ServerTrust},
+    udp::UdpMessageStream,
+};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+
+use crate::{Args, AssetHandles, CurrentRole, HandleErrors, Role, Transport};
+
+/// How long to wait before reconnecting after losing the server, at first and at most.
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+/// Connecting blocks the frame, so don't wait long.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(250);
+/// How many snapshots are kept for later ones to build on. The server sends the whole state
+/// rather than build on one it has forgotten, so this only needs to cover what it remembers.
+const SNAPSHOTS_KEPT: usize = 16;
+
+/// All the entities that come from the game state.
+type GameEntityQuery<'world, 'state> = Query<'world, 'state, Entity, Or<(With<Hoop>, With<Ball>)>>;
+
+/// The connection to the server, over the transport picked at startup.
+enum ServerStream {
+    Tcp(MessageStream<TcpStream>),
+    /// Boxed, as the TLS state is far bigger than the rest.
+    Tls(Box<MessageStream<StreamOwned<ClientConnection, TcpStream>>>),
+    Udp(UdpMessageStream<ToServerMessage, ToClientMessage>),
+}
+
+impl ServerStream {
+    fn write_message(&mut self, message: ToServerMessage) -> anyhow::Result<()> {
+        match self {
+            ServerStream::Tcp(stream) => stream.write_message(&message),
+            ServerStream::Tls(stream) => stream.write_message(&message),
+            ServerStream::Udp(stream) => stream.write_message(message),
+        }
+    }
+
+    fn read_messages(&mut self) -> anyhow::Result<Vec<ToClientMessage>> {
+        match self {
+            ServerStream::Tcp(stream) => stream.read_messages(),
+            ServerStream::Tls(stream) => stream.read_messages(),
+            ServerStream::Udp(stream) => stream.read_messages(),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct ServerConnection {
+    /// `None` while disconnected.
+    stream: Option<ServerStream>,
+    transport: Transport,
+    /// `None` for plain TCP.
+    tls: Option<Arc<ClientConfig>>,
+    server: String,
+    port: u16,
+    passphrase_hash: Option<PassphraseHash>,
+    /// The game to join once connected, which is also the one to rejoin after reconnecting.
+    game: Option<GameRef>,
+    /// Hashed with the game's salt to join, once that's known.
+    game_password: Option<String>,
+    /// Given by the server when it refuses a join for want of it, unless we made the game.
+    game_salt: Option<Salt>,
+    preferred_role: Option<PreferredRole>,
+    /// What the server wants game passwords mixed with on this connection.
+    password_nonce: Option<Nonce>,
+    /// Given by the server when we join, to get our role back if we have to reconnect.
+    session: Option<SessionToken>,
+    /// The game as of the last snapshot and the updates since. `None` until the first snapshot of
+    /// the game joined.
+    state: Option<GameState>,
+    /// The tick of the snapshot `state` was last brought up to.
+    state_tick: Option<u64>,
+    /// Recent snapshots by tick, oldest first.
+    snapshots: VecDeque<(u64, GameState)>,
+    next_attempt: Instant,
+    backoff: Duration,
+}
+
+impl ServerConnection {
+    pub fn send(&mut self, message: ToServerMessage) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        if let Err(e) = stream.write_message(message) {
+            self.disconnect(e);
+        }
+    }
+
+    /// Joins a game picked in the lobby.
+    pub fn join(&mut self, game: GameRef, password: Option<String>) {
+        self.game = Some(game);
+        self.game_password = password;
+        self.game_salt = None;
+        self.session = None;
+        self.send_join();
+    }
+
+    /// Creates a game, to join once the server has made it.
+    pub fn create_game(&mut self, name: String, private: bool, password: Option<String>) {
+        let salt = rand::random();
+        let password_to_set = password
+            .as_deref()
+            .map(|password| GamePassword::new(password, salt));
+        self.game_password = password;
+        self.game_salt = Some(salt);
+        self.send(ToServerMessage::CreateGame {
+            name,
+            private,
+            password: password_to_set,
+        });
+    }
+
+    fn send_join(&mut self) {
+        let Some(game) = self.game.clone() else {
+            return;
+        };
+        // The game comes with the next snapshot, which may well be of another one.
+        self.forget_state();
+        let password = match (&self.game_password, self.game_salt, self.password_nonce) {
+            (Some(password), Some(salt), Some(nonce)) => {
+                Some(PassphraseHash::salted(password, &salt).respond(&nonce))
+            }
+            _ => None,
+        };
+        let session = self.session;
+        let preferred_role = self.preferred_role;
+        self.send(ToServerMessage::JoinGame {
+            game,
+            session,
+            password,
+            preferred_role,
+        });
+    }
+
+    /// Rebuilds the snapshot for `tick` from the one it's based on, and lets the server know it
+    /// arrived.
+    fn receive_snapshot(
+        &mut self,
+        tick: u64,
+        baseline: Option<u64>,
+        delta: &StateDelta,
+    ) -> Option<GameState> {
+        let mut state = match baseline {
+            None => GameState::default(),
+            Some(baseline) => {
+                let Some((_, state)) = self.snapshots.iter().find(|(kept, _)| *kept == baseline)
+                else {
+                    warn!("Snapshot {tick} builds on snapshot {baseline}, which is gone");
+                    return None;
+                };
+                state.clone()
+            }
+        };
+        delta.apply(&mut state);
+        self.snapshots.push_back((tick, state.clone()));
+        if self.snapshots.len() > SNAPSHOTS_KEPT {
+            self.snapshots.pop_front();
+        }
+        self.send(ToServerMessage::AckSnapshot { tick });
+        Some(state)
+    }
+
+    fn forget_state(&mut self) {
+        self.state = None;
+        self.state_tick = None;
+        self.snapshots.clear();
+    }
+
+    fn disconnect(&mut self, error: anyhow::Error) {
+        warn!(
+            "Lost connection to server (retrying in {:?}): {error:#}",
+            self.backoff
+        );
+        self.stream = None;
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+
+    fn connect(&mut self) {
+        info!(
+            "Connecting to {}:{} over {:?}{}",
+            self.server,
+            self.port,
+            self.transport,
+            if self.tls.is_some() { " with TLS" } else { "" }
+        );
+        match establish_connection(self.transport, self.tls.as_ref(), &self.server, self.port) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.forget_state();
+                send_hello(self);
+                info!("Connected");
+            }
+            Err(e) => self.disconnect(e),
+        }
+    }
+}
+
+pub fn setup(app: &mut App) {
+    app.add_systems(Startup, setup_connect)
+        .add_systems(Update, (maintain_connection, update_from_server).chain());
+}
+
+fn setup_connect(mut commands: Commands) {
+    let args = Args::parse();
+    let tls = tls_config(&args);
+    commands.insert_resource(ServerConnection {
+        stream: None,
+        transport: args.transport,
+        tls,
+        server: args.server,
+        port: args.port,
+        passphrase_hash: args.passphrase.as_deref().map(PassphraseHash::new),
+        game: args.game,
+        game_password: args.game_password,
+        game_salt: None,
+        preferred_role: args.role,
+        password_nonce: None,
+        session: None,
+        state: None,
+        state_tick: None,
+        snapshots: VecDeque::new(),
+        next_attempt: Instant::now(),
+        backoff: MIN_RECONNECT_BACKOFF,
+    });
+}
+
+/// Sets up TLS as asked for on the command line, exiting with a usage error if it can't be.
+fn tls_config(args: &Args) -> Option<Arc<ClientConfig>> {
+    if !args.tls {
+        return None;
+    }
+    let exit = |kind, message: String| -> ! { Args::command().error(kind, message).exit() };
+    if !matches!(args.transport, Transport::Tcp) {
+        exit(
+            ErrorKind::ArgumentConflict,
+            "--tls only works with --transport tcp".to_owned(),
+        );
+    }
+    let trust = match (&args.tls_ca, args.tls_pin) {
+        (Some(path), _) => match tls::load_certificates(path) {
+            Ok(certificates) => ServerTrust::CustomCa(certificates),
+            Err(e) => exit(ErrorKind::InvalidValue, format!("{e:#}")),
+        },
+        (None, Some(pin)) => ServerTrust::Pinned(pin),
+        (None, None) => ServerTrust::PublicRoots,
+    };
+    match tls::client_config(trust) {
+        Ok(config) => Some(config),
+        Err(e) => exit(ErrorKind::InvalidValue, format!("{e:#}")),
+    }
+}
+
+fn maintain_connection(mut server: ResMut<ServerConnection>) {
+    if server.stream.is_none() && Instant::now() >= server.next_attempt {
+        server.connect();
+    }
+}
+
+// Systems take what they need as arguments, so this one that handles every message needs a lot.
+#[allow(clippy::too_many_arguments)]
+fn update_from_server(
+    mut commands: Commands,
+    mut server: ResMut<ServerConnection>,
+    mut current_role: ResMut<CurrentRole>,
+    mut lobby: ResMut<Lobby>,
+    mut swaps: ResMut<RoleSwaps>,
+    mut hud: ResMut<MatchHud>,
+    mut hoop_prediction: ResMut<HoopPrediction>,
+    mut interpolation: ResMut<Interpolation>,
+    asset_handles: Res<AssetHandles>,
+    mut hoops_and_balls: ParamSet<(HoopQuery, BallQuery)>,
+    existing_entities: GameEntityQuery,
+) {
+    let Some(stream) = &mut server.stream else {
+        return;
+    };
+    let messages = match stream.read_messages() {
+        Ok(messages) => messages,
+        Err(e) => {
+            server.disconnect(e);
+            return;
+        }
+    };
+    for message in messages {
+        // A tick moves the clock on for what follows it, and its moves are handled as events are.
+        if let ToClientMessage::Tick { tick, .. } = &message {
+            interpolation.tick(*tick);
+        }
+        match message {
+            ToClientMessage::HelloAccepted {
+                protocol_version,
+                capabilities,
+                password_nonce,
+            } => {
+                if !SUPPORTED_PROTOCOL_VERSIONS.contains(protocol_version) {
+                    error!(
+                        "Server speaks protocol version {protocol_version}, but this client supports {}",
+                        SUPPORTED_PROTOCOL_VERSIONS
+                    );
+                    std::process::exit(1);
+                }
+                info!("Connected with protocol version {protocol_version} and capabilities {capabilities:?}");
+                server.backoff = MIN_RECONNECT_BACKOFF;
+                server.password_nonce = Some(password_nonce);
+                if server.game.is_some() {
+                    server.send_join();
+                } else {
+                    lobby.show();
+                    server.send(ToServerMessage::ListGames);
+                }
+            }
+            ToClientMessage::GameList { games } => {
+                lobby.set_games(games);
+            }
+            ToClientMessage::GameCreated { game } => {
+                info!("Created game {} (join code {})", game.name, game.code);
+                // Private games can only be joined by code. The password and its salt are ours.
+                server.game = Some(GameRef::Code(game.code));
+                server.session = None;
+                server.send_join();
+            }
+            ToClientMessage::LobbyError { error } => {
+                if let LobbyError::WrongGamePassword { salt, .. } = error {
+                    // Without the salt there was no way to get the password right, so try again.
+                    if server.game_password.is_some() && server.game_salt.is_none() {
+                        server.game_salt = Some(salt);
+                        server.send_join();
+                        continue;
+                    }
+                }
+                warn!("Lobby error: {error}");
+                // Nothing left to rejoin, so pick another game.
+                server.game = None;
+                server.session = None;
+                current_role.0 = Role::Unknown;
+                if let LobbyError::WrongGamePassword { game, .. } = &error {
+                    lobby.ask_password(game.clone());
+                }
+                lobby.set_error(error.to_string());
+                lobby.show();
+                server.send(ToServerMessage::ListGames);
+            }
+            ToClientMessage::AuthChallenge { nonce } => {
+                trace!("Answering auth challenge");
+                let response = server
+                    .passphrase_hash
+                    .context("Server requires a passphrase (use --passphrase)")
+                    .handle()
+                    .respond(&nonce);
+                server.send(ToServerMessage::AuthResponse { response });
+            }
+            ToClientMessage::Rejected { reason } => {
+                error!("Server rejected connection: {reason}");
+                std::process::exit(1);
+            }
+            ToClientMessage::EstablishAsHoop { session } => {
+                trace!("I'm a hoop");
+                current_role.0 = Role::Hoop;
+                server.session = Some(session);
+                lobby.hide();
+                swaps.role_changed();
+                hoop_prediction.reset();
+                interpolation.forget_hoop();
+            }
+            ToClientMessage::EstablishAsBall { id, session } => {
+                trace!("I'm a ball");
+                current_role.0 = Role::Ball { id };
+                server.session = Some(session);
+                lobby.hide();
+                swaps.role_changed();
+                hoop_prediction.reset();
+                interpolation.forget_hoop();
+            }
+            ToClientMessage::Tick { tick, updates } | ToClientMessage::Events { tick, updates } => {
+                // Until the first snapshot there's nothing to apply them to. Over UDP, they can
+                // come after a snapshot that already has them.
+                if server
+                    .state_tick
+                    .is_some_and(|state_tick| tick <= state_tick)
+                {
+                    continue;
+                }
+                let Some(state) = &mut server.state else {
+                    continue;
+                };
+                for update in updates {
+                    update.apply(state);
+                    hud.apply(&update);
+                    match update {
+                        UpdateState::MoveHoop { x, sequence } => {
+                            if let Role::Hoop = current_role.0 {
+                                if let Some(x) = hoop_prediction.reconcile(x, sequence) {
+                                    move_hoop(&mut hoops_and_balls.p0(), x);
+                                }
+                            } else {
+                                interpolation.move_hoop(x);
+                            }
+                        }
+                        UpdateState::AddBall { id, position } => {
+                            add_ball(
+                                &mut commands,
+                                &hoops_and_balls.p1(),
+                                id,
+                                position,
+                                &asset_handles.ball_assets,
+                            );
+                            interpolation.move_ball(id, position);
+                        }
+                        UpdateState::RemoveBall { id } => {
+                            remove_ball(&mut commands, id, &mut hoops_and_balls.p1());
+                            interpolation.remove_ball(id);
+                        }
+                        UpdateState::MoveBall { id, position } => {
+                            interpolation.move_ball(id, position);
+                        }
+                        UpdateState::ResetBall { id, position } => {
+                            reset_ball(id, &mut hoops_and_balls.p1());
+                            interpolation.reset_ball(id, position);
+                        }
+                        UpdateState::Scored { id, score } => {
+                            info!("Ball {id} scored (score: {score})");
+                        }
+                        UpdateState::Noped { id, nopes } => {
+                            info!("Ball {id} was noped (nopes: {nopes})");
+                        }
+                        UpdateState::ScoresReset => {
+                            info!("New match");
+                        }
+                        UpdateState::ScoresSwapped { .. } | UpdateState::HoopScoreCleared => {}
+                    }
+                }
+            }
+            ToClientMessage::Snapshot {
+                tick,
+                baseline,
+                delta,
+                hoop_sequence,
+            } => {
+                let Some(state) = server.receive_snapshot(tick, baseline, &delta) else {
+                    continue;
+                };
+                // One that took longer than a later one would take the game back.
+                if server
+                    .state_tick
+                    .is_some_and(|state_tick| tick <= state_tick)
+                {
+                    continue;
+                }
+                server.state_tick = Some(tick);
+                if server.state.as_ref() == Some(&state) {
+                    continue;
+                }
+                match server.state.take() {
+                    // Whatever is there is from another game.
+                    None => {
+                        for entity in &existing_entities {
+                            commands.entity(entity).despawn();
+                        }
+                        interpolation.reset();
+                        add_hoop(&mut commands, state.hoop_x, &asset_handles.hoop_assets);
+                        interpolation.move_hoop(state.hoop_x);
+                        for (&id, &ball) in &state.ball_positions {
+                            spawn_ball(&mut commands, id, ball, &asset_handles.ball_assets);
+                            interpolation.move_ball(id, ball);
+                        }
+                    }
+                    // A missed update, as happens whenever a tick is lost over UDP. What's still
+                    // there moves on from where it's shown, so only what came or went is redone.
+                    Some(old) => {
+                        debug!("Out of step with the server at tick {tick}, catching up");
+                        if old.hoop_x != state.hoop_x {
+                            if let Role::Hoop = current_role.0 {
+                                // The moves it doesn't have yet still go on top.
+                                let reconciled =
+                                    hoop_prediction.reconcile(state.hoop_x, hoop_sequence);
+                                if let Some(x) = reconciled {
+                                    move_hoop(&mut hoops_and_balls.p0(), x);
+                                }
+                            } else {
+                                interpolation.move_hoop(state.hoop_x);
+                            }
+                        }
+                        for &id in old.ball_positions.keys() {
+                            if !state.ball_positions.contains_key(&id) {
+                                remove_ball(&mut commands, id, &mut hoops_and_balls.p1());
+                                interpolation.remove_ball(id);
+                            }
+                        }
+                        for (&id, &ball) in &state.ball_positions {
+                            match old.ball_positions.get(&id) {
+                                None => {
+                                    add_ball(
+                                        &mut commands,
+                                        &hoops_and_balls.p1(),
+                                        id,
+                                        ball,
+                                        &asset_handles.ball_assets,
+                                    );
+                                    interpolation.move_ball(id, ball);
+                                }
+                                Some(&was) if was != ball => interpolation.move_ball(id, ball),
+                                Some(_) => {}
+                            }
+                        }
+                    }
+                }
+                hud.set_state(state.clone());
+                server.state = Some(state);
+            }
+            ToClientMessage::PhaseChanged {
+                phase,
+                rounds,
+                remaining_secs,
+            } => {
+                trace!("Match phase: {phase:?}");
+                hud.set_phase(phase, rounds, remaining_secs);
+            }
+            ToClientMessage::MatchOver {
+                standings,
+                hoop_nopes,
+                winner,
+            } => {
+                info!("Match over: {winner:?}");
+                hud.match_over(&standings, hoop_nopes, winner);
+            }
+            ToClientMessage::CommandRejected { error } => {
+                warn!("Server rejected a command: {error}");
+                if let (
+                    CommandError::NotPlaying | CommandError::OutOfShots { .. },
+                    Role::Ball { id },
+                ) = (&error, &current_role.0)
+                {
+                    shot_rejected(*id, &mut hoops_and_balls.p1());
+                }
+            }
+            ToClientMessage::RoleSwapRequested { ball_id } => {
+                info!("Ball {ball_id} asked for the hoop");
+                swaps.requested(ball_id);
+            }
+            ToClientMessage::RoleSwapAnswered { accepted } => {
+                swaps.set_status(if accepted {
+                    "The hoop is yours next round".to_owned()
+                } else {
+                    "The hoop turned down the swap".to_owned()
+                });
+            }
+            ToClientMessage::RolesSwapped { ball_id } => {
+                info!("Ball {ball_id}'s player took the hoop");
+            }
+            ToClientMessage::EstablishAsObserver { session } => {
+                trace!("I'm an observer");
+                current_role.0 = Role::Observer;
+                server.session = Some(session);
+                lobby.hide();
+                swaps.role_changed();
+                hoop_prediction.reset();
+                interpolation.forget_hoop();
+            }
+        }
+    }
+}
+
+fn establish_connection(
+    transport: Transport,
+    tls: Option<&Arc<ClientConfig>>,
+    server: &str,
+    port: u16,
+) -> anyhow::Result<ServerStream> {
+    let address = (server, port)
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("No address found for {server}"))?;
+    match transport {
+        Transport::Tcp => {
+            let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            let Some(tls) = tls else {
+                stream.set_nonblocking(true)?;
+                return Ok(ServerStream::Tcp(MessageStream::new(stream)));
+            };
+            // The handshake blocks the frame as well, so it gets no longer than connecting.
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            let server_name = ServerName::try_from(server.to_owned())
+                .with_context(|| format!("{server} can't be checked against a certificate"))?;
+            let mut connection = ClientConnection::new(tls.clone(), server_name)?;
+            connection
+                .complete_io(&mut stream)
+                .context("TLS handshake failed")?;
+            stream.set_nonblocking(true)?;
+            let stream = StreamOwned::new(connection, stream);
+            Ok(ServerStream::Tls(Box::new(MessageStream::new(stream))))
+        }
+        // There's nothing to wait for: the server hears of the connection with the hello.
+        Transport::Udp => Ok(ServerStream::Udp(UdpMessageStream::connect(address)?)),
+    }
+}
+
+fn send_hello(server: &mut ServerConnection) {
+    server.send(ToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    });
+}
@@ -1,72 +1,190 @@
-use bevy::{
-    prelude::*,
-    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
-};
-use nope_the_hoop_proto::message::{HorizontalDirection, ToServerMessage};
-
-use crate::{connection::ServerConnection, CurrentRole, Role};
-
-#[derive(Component)]
-pub struct Hoop;
-
-pub type HoopQuery<'world, 'state, 'a> = Query<'world, 'state, (&'a Hoop, &'a mut Transform)>;
-
-pub fn setup(app: &mut App) {
-    app.add_systems(Update, handle_input);
-}
-
-pub struct AssetHandles {
-    hoop_mesh: Mesh2dHandle,
-    hoop_material: Handle<ColorMaterial>,
-}
-
-impl AssetHandles {
-    pub fn create(materials: &mut Assets<ColorMaterial>, meshes: &mut Assets<Mesh>) -> Self {
-        let hoop_material = materials.add(ColorMaterial::from(Color::GRAY));
-        let hoop_mesh = meshes.add(Rectangle::new(50., 10.)).into();
-        Self {
-            hoop_mesh,
-            hoop_material,
-        }
-    }
-}
-
-pub fn add_hoop(commands: &mut Commands, hoop_x: f32, asset_handles: &AssetHandles) {
-    commands.spawn((
-        MaterialMesh2dBundle {
-            mesh: asset_handles.hoop_mesh.clone(),
-            material: asset_handles.hoop_material.clone(),
-            transform: Transform::from_translation(Vec3::new(hoop_x, 0., 0.)),
-            ..default()
-        },
-        Hoop,
-    ));
-}
-
-pub fn move_hoop(hoops: &mut HoopQuery, x: f32) {
-    hoops.single_mut().1.translation.x = x;
-}
-
-fn handle_input(
-    mut server: ResMut<ServerConnection>,
-    current_role: Res<CurrentRole>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    time: Res<Time>,
-) {
-    let Role::Hoop = current_role.0 else {
-        return;
-    };
-    if keyboard_input.pressed(KeyCode::ArrowLeft) {
-        send_hoop_movement(&mut server, HorizontalDirection::Left, &time);
-    }
-    if keyboard_input.pressed(KeyCode::ArrowRight) {
-        send_hoop_movement(&mut server, HorizontalDirection::Right, &time);
-    }
-}
-
-fn send_hoop_movement(server: &mut ServerConnection, direction: HorizontalDirection, time: &Time) {
-    server.send(ToServerMessage::MoveHoop {
-        direction,
-        seconds_pressed: time.delta_seconds(),
-    });
-}
+use std::collections::VecDeque;
+
+use bevy::{
+    prelude::*,
+    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
+};
+use nope_the_hoop_proto::message::{HorizontalDirection, ToServerMessage};
+use nope_the_hoop_sim::{moved_hoop_x, HOOP_HEIGHT, HOOP_WIDTH};
+
+use crate::{connection::ServerConnection, CurrentRole, Role};
+
+#[derive(Component)]
+pub struct Hoop;
+
+pub type HoopQuery<'world, 'state, 'a> = Query<'world, 'state, (&'a Hoop, &'a mut Transform)>;
+
+/// About a second of frames. A move the server still hasn't confirmed by then is most likely lost
+/// with the connection, and the next correction puts the hoop where it really is anyway.
+const MAX_PENDING_MOVES: usize = 120;
+
+/// The hoop player's moves, shown straight away rather than when the server gets around to them.
+#[derive(Resource, Default)]
+pub struct HoopPrediction {
+    last_sequence: u32,
+    /// The latest move the server said it has.
+    confirmed: u32,
+    /// Moves the server hasn't confirmed yet, oldest first.
+    pending: VecDeque<HoopMove>,
+}
+
+struct HoopMove {
+    sequence: u32,
+    direction: HorizontalDirection,
+    seconds_pressed: f32,
+}
+
+impl HoopPrediction {
+    /// Starts counting again, as the server does for every new hoop connection.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn push(&mut self, pending: HoopMove) {
+        if self.pending.len() == MAX_PENDING_MOVES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(pending);
+    }
+
+    /// Where the hoop is once the moves after `sequence` are replayed on top of the server's `x`,
+    /// unless the server already said where it was after a later move.
+    pub fn reconcile(&mut self, x: f32, sequence: u32) -> Option<f32> {
+        if sequence < self.confirmed {
+            return None;
+        }
+        self.confirmed = sequence;
+        self.pending.retain(|pending| pending.sequence > sequence);
+        Some(self.pending.iter().fold(x, |x, pending| {
+            moved_hoop_x(x, pending.direction, pending.seconds_pressed)
+        }))
+    }
+}
+
+pub fn setup(app: &mut App) {
+    app.init_resource::<HoopPrediction>()
+        .add_systems(Update, handle_input);
+}
+
+pub struct AssetHandles {
+    hoop_mesh: Mesh2dHandle,
+    hoop_material: Handle<ColorMaterial>,
+}
+
+impl AssetHandles {
+    pub fn create(materials: &mut Assets<ColorMaterial>, meshes: &mut Assets<Mesh>) -> Self {
+        let hoop_material = materials.add(ColorMaterial::from(Color::GRAY));
+        let hoop_mesh = meshes.add(Rectangle::new(HOOP_WIDTH, HOOP_HEIGHT)).into();
+        Self {
+            hoop_mesh,
+            hoop_material,
+        }
+    }
+}
+
+pub fn add_hoop(commands: &mut Commands, hoop_x: f32, asset_handles: &AssetHandles) {
+    commands.spawn((
+        MaterialMesh2dBundle {
+            mesh: asset_handles.hoop_mesh.clone(),
+            material: asset_handles.hoop_material.clone(),
+            transform: Transform::from_translation(Vec3::new(hoop_x, 0., 0.)),
+            ..default()
+        },
+        Hoop,
+    ));
+}
+
+pub fn move_hoop(hoops: &mut HoopQuery, x: f32) {
+    if let Ok((_, mut transform)) = hoops.get_single_mut() {
+        transform.translation.x = x;
+    }
+}
+
+fn handle_input(
+    mut server: ResMut<ServerConnection>,
+    mut prediction: ResMut<HoopPrediction>,
+    mut hoops: HoopQuery,
+    current_role: Res<CurrentRole>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+) {
+    let Role::Hoop = current_role.0 else {
+        return;
+    };
+    let Ok((_, mut transform)) = hoops.get_single_mut() else {
+        return;
+    };
+    // One move a frame. With both keys held, a move each way would cancel out here, but the server
+    // budgets presses against the time that passed and would cut the pair short.
+    let direction = match (
+        keyboard_input.pressed(KeyCode::ArrowLeft),
+        keyboard_input.pressed(KeyCode::ArrowRight),
+    ) {
+        (true, false) => HorizontalDirection::Left,
+        (false, true) => HorizontalDirection::Right,
+        _ => return,
+    };
+    let seconds_pressed = time.delta_seconds();
+    prediction.last_sequence += 1;
+    let sequence = prediction.last_sequence;
+    server.send(ToServerMessage::MoveHoop {
+        direction,
+        seconds_pressed,
+        sequence,
+    });
+    prediction.push(HoopMove {
+        sequence,
+        direction,
+        seconds_pressed,
+    });
+    transform.translation.x = moved_hoop_x(transform.translation.x, direction, seconds_pressed);
+}
+
+#[cfg(test)]
+mod tests {
+    use nope_the_hoop_sim::HOOP_SPEED;
+
+    use super::*;
+
+    fn prediction(moves: u32) -> HoopPrediction {
+        let mut prediction = HoopPrediction::default();
+        for sequence in 1..=moves {
+            prediction.push(HoopMove {
+                sequence,
+                direction: HorizontalDirection::Right,
+                seconds_pressed: 0.1,
+            });
+        }
+        prediction.last_sequence = moves;
+        prediction
+    }
+
+    #[test]
+    fn replays_unconfirmed_moves() {
+        let mut prediction = prediction(3);
+        // A snapshot that has the first move only.
+        let x = prediction.reconcile(100., 1).unwrap();
+        assert!((x - (100. + 2. * 0.1 * HOOP_SPEED)).abs() < 1e-3, "{x}");
+        // One older than what's been confirmed says nothing new.
+        prediction.reconcile(110., 2).unwrap();
+        assert_eq!(prediction.reconcile(100., 1), None);
+        assert_eq!(prediction.pending.len(), 1);
+    }
+
+    #[test]
+    fn pending_moves_are_capped() {
+        let mut prediction = prediction(0);
+        for sequence in 1..=2 * MAX_PENDING_MOVES as u32 {
+            prediction.push(HoopMove {
+                sequence,
+                direction: HorizontalDirection::Left,
+                seconds_pressed: 0.01,
+            });
+        }
+        assert_eq!(prediction.pending.len(), MAX_PENDING_MOVES);
+        assert_eq!(
+            prediction.pending.front().unwrap().sequence,
+            MAX_PENDING_MOVES as u32 + 1
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use nope_the_hoop_proto::state::{GameState, MatchPhase, MatchWinner, Standing, UpdateState};
+
+use crate::{CurrentRole, Role};
+
+/// What the HUD shows about the match, kept up to date from the server's messages.
+#[derive(Resource, Default)]
+pub struct MatchHud {
+    phase: Option<MatchPhase>,
+    rounds: u32,
+    phase_ends: Option<Instant>,
+    state: Option<GameState>,
+    /// How the last match ended, shown until the next one starts.
+    result: Option<String>,
+}
+
+impl MatchHud {
+    pub fn set_phase(&mut self, phase: MatchPhase, rounds: u32, remaining_secs: Option<f32>) {
+        if phase == (MatchPhase::Countdown { round: 1 }) {
+            self.result = None;
+        }
+        self.phase = Some(phase);
+        self.rounds = rounds;
+        self.phase_ends = remaining_secs.map(|secs| Instant::now() + Duration::from_secs_f32(secs));
+    }
+
+    pub fn set_state(&mut self, state: GameState) {
+        self.state = Some(state);
+    }
+
+    pub fn apply(&mut self, update: &UpdateState) {
+        if let Some(state) = &mut self.state {
+            update.apply(state);
+        }
+    }
+
+    pub fn match_over(&mut self, standings: &[Standing], hoop_nopes: u32, winner: MatchWinner) {
+        let winner = match winner {
+            MatchWinner::Player { ball_id: Some(id) } => format!("Ball {id} wins!"),
+            MatchWinner::Player { ball_id: None } => "The hoop's player wins!".to_owned(),
+            MatchWinner::Hoop => "The hoop wins!".to_owned(),
+            MatchWinner::Draw => "It's a draw!".to_owned(),
+        };
+        let standings = standings
+            .iter()
+            .map(|standing| match standing.ball_id {
+                Some(id) => format!("ball {id}: {}", standing.score),
+                None => format!("hoop: {}", standing.score),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.result = Some(format!("{winner} ({standings}; hoop nopes: {hoop_nopes})"));
+    }
+}
+
+#[derive(Component)]
+struct HudText;
+
+pub fn setup(app: &mut App) {
+    app.init_resource::<MatchHud>()
+        .add_systems(Startup, setup_hud_text)
+        .add_systems(Update, show_hud);
+}
+
+fn setup_hud_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(12.),
+            right: Val::Px(12.),
+            ..default()
+        }),
+        HudText,
+    ));
+}
+
+/// Redrawn every frame, since the time left keeps changing.
+fn show_hud(
+    hud: Res<MatchHud>,
+    current_role: Res<CurrentRole>,
+    mut text: Query<&mut Text, With<HudText>>,
+) {
+    let Some(phase) = hud.phase else {
+        return;
+    };
+    let rounds = hud.rounds;
+    let mut lines = vec![match phase {
+        MatchPhase::WaitingForPlayers => "Waiting for players".to_owned(),
+        MatchPhase::Countdown { round } => format!("Round {round}/{rounds} starting"),
+        MatchPhase::Playing { round } => format!("Round {round}/{rounds}"),
+        MatchPhase::RoundOver { round } => format!("Round {round}/{rounds} over"),
+        MatchPhase::MatchOver => "Match over".to_owned(),
+    }];
+    if let Some(phase_ends) = hud.phase_ends {
+        let remaining = phase_ends.saturating_duration_since(Instant::now());
+        lines.push(format!("{}s left", remaining.as_secs_f32().ceil()));
+    }
+    if let Some(state) = &hud.state {
+        let mut balls: Vec<_> = state.ball_positions.keys().copied().collect();
+        balls.sort();
+        for id in balls {
+            let score = state.scores.get(&id).copied().unwrap_or(0);
+            let you = matches!(current_role.0, Role::Ball { id: mine } if mine == id);
+            lines.push(format!(
+                "Ball {id}{}: {score}",
+                if you { " (you)" } else { "" }
+            ));
+        }
+        let you = matches!(current_role.0, Role::Hoop);
+        lines.push(format!(
+            "Hoop{}: {}, nopes: {}",
+            if you { " (you)" } else { "" },
+            state.hoop_score,
+            state.nopes
+        ));
+    }
+    lines.extend(hud.result.clone());
+    text.single_mut().sections[0].value = lines.join("\n");
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use clap::Parser;
+use nope_the_hoop_proto::state::Point;
+use nope_the_hoop_sim::{HOOP_MAX_X, HOOP_MIN_X, TICK_SECONDS};
+
+use crate::{
+    ball::{move_ball, BallQuery},
+    hoop::{move_hoop, HoopQuery},
+    Args, CurrentRole, Role,
+};
+
+/// The furthest a position is carried on past the last one received, in seconds.
+const MAX_EXTRAPOLATION: f64 = 0.25;
+/// How much of the difference between the clock estimate and each tick's arrival is taken in.
+const CLOCK_SMOOTHING: f64 = 0.05;
+/// A clock estimate this far off, in seconds, is started over rather than smoothed, as after
+/// joining another game.
+const MAX_CLOCK_DRIFT: f64 = 1.;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// Server time, in seconds since its first tick.
+    time: f64,
+    position: Vec2,
+    /// How fast it was moving on the way to this sample, to extrapolate with.
+    velocity: Vec2,
+    /// Whether it got here by being put back rather than by moving, so there's nothing in between.
+    jump: bool,
+}
+
+/// The positions received for one hoop or ball, oldest first.
+#[derive(Debug, Default)]
+struct Samples(VecDeque<Sample>);
+
+impl Samples {
+    fn push(&mut self, time: f64, position: Vec2, jump: bool) {
+        // Only the latest position in a tick counts.
+        while self.0.back().is_some_and(|last| last.time >= time) {
+            self.0.pop_back();
+        }
+        let velocity = match self.0.back() {
+            Some(last) if !jump => (position - last.position) / (time - last.time) as f32,
+            _ => Vec2::ZERO,
+        };
+        self.0.push_back(Sample {
+            time,
+            position,
+            velocity,
+            jump,
+        });
+    }
+
+    /// Marks it as having stayed put at `time` if it was moving but nothing came for it since, so
+    /// that it isn't carried on past where it stopped.
+    fn hold(&mut self, time: f64) {
+        if let Some(&last) = self.0.back() {
+            if last.time < time && last.velocity != Vec2::ZERO {
+                self.push(time, last.position, false);
+            }
+        }
+    }
+
+    /// Where it was at `time`, dropping the samples that are no longer needed to tell.
+    fn position_at(&mut self, time: f64) -> Option<Vec2> {
+        while self.0.len() > 1 && self.0[1].time <= time {
+            self.0.pop_front();
+        }
+        let from = *self.0.front()?;
+        if time <= from.time {
+            return Some(from.position);
+        }
+        let Some(to) = self.0.get(1) else {
+            let ahead = (time - from.time).min(MAX_EXTRAPOLATION);
+            return Some(from.position + from.velocity * ahead as f32);
+        };
+        if to.jump {
+            return Some(from.position);
+        }
+        let t = (time - from.time) / (to.time - from.time);
+        Some(from.position.lerp(to.position, t as f32))
+    }
+
+    /// How many samples are waiting to be shown at `time`.
+    fn buffered(&self, time: f64) -> usize {
+        self.0.iter().filter(|sample| sample.time > time).count()
+    }
+
+    fn extrapolating(&self, time: f64) -> bool {
+        self.0
+            .back()
+            .is_some_and(|last| last.time < time && last.velocity != Vec2::ZERO)
+    }
+}
+
+/// Other players' hoop and balls, shown a little in the past so that they move smoothly however
+/// unevenly their updates arrive.
+#[derive(Resource)]
+pub struct Interpolation {
+    delay: f64,
+    epoch: Instant,
+    /// The server time of the tick the latest updates belong to.
+    tick_time: f64,
+    /// Server time minus local time, estimated from when ticks arrive. `None` until the first.
+    clock_offset: Option<f64>,
+    hoop: Samples,
+    balls: HashMap<u32, Samples>,
+    show_debug: bool,
+}
+
+impl Interpolation {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay: delay.as_secs_f64(),
+            epoch: Instant::now(),
+            tick_time: 0.,
+            clock_offset: None,
+            hoop: Samples::default(),
+            balls: HashMap::new(),
+            show_debug: false,
+        }
+    }
+
+    /// Forgets every position, for a new game state. Positions that come with it are shown as they
+    /// are until something moves.
+    pub fn reset(&mut self) {
+        self.hoop = Samples::default();
+        self.balls.clear();
+    }
+
+    pub fn tick(&mut self, tick: u64) {
+        // Whatever didn't move in the last tick stopped where it was.
+        self.hoop.hold(self.tick_time);
+        for samples in self.balls.values_mut() {
+            samples.hold(self.tick_time);
+        }
+        self.tick_time = tick as f64 * TICK_SECONDS as f64;
+        let offset = self.tick_time - self.local_time();
+        self.clock_offset = Some(match self.clock_offset {
+            Some(estimate) if (offset - estimate).abs() < MAX_CLOCK_DRIFT => {
+                estimate + (offset - estimate) * CLOCK_SMOOTHING
+            }
+            _ => offset,
+        });
+    }
+
+    pub fn move_hoop(&mut self, x: f32) {
+        self.hoop.push(self.tick_time, Vec2::new(x, 0.), false);
+    }
+
+    /// Forgets the hoop's positions when the player takes or gives up the hoop, which the server
+    /// only sends positions for when it moves.
+    pub fn forget_hoop(&mut self) {
+        self.hoop = Samples::default();
+    }
+
+    pub fn move_ball(&mut self, id: u32, position: Point) {
+        self.push_ball(id, position, false);
+    }
+
+    /// Puts a ball straight back where it was reset to, once it's shown that far along.
+    pub fn reset_ball(&mut self, id: u32, position: Point) {
+        self.push_ball(id, position, true);
+    }
+
+    fn push_ball(&mut self, id: u32, position: Point, jump: bool) {
+        let time = self.tick_time;
+        self.balls
+            .entry(id)
+            .or_default()
+            .push(time, Vec2::new(position.x, position.y), jump);
+    }
+
+    pub fn remove_ball(&mut self, id: u32) {
+        self.balls.remove(&id);
+    }
+
+    fn local_time(&self) -> f64 {
+        self.epoch.elapsed().as_secs_f64()
+    }
+
+    /// The server time to show things at.
+    fn render_time(&self) -> Option<f64> {
+        Some(self.local_time() + self.clock_offset? - self.delay)
+    }
+}
+
+#[derive(Component)]
+struct DebugText;
+
+pub fn setup(app: &mut App) {
+    app.add_systems(Startup, setup_interpolation)
+        .add_systems(Update, (interpolate, show_debug));
+}
+
+fn setup_interpolation(mut commands: Commands) {
+    let args = Args::parse();
+    commands.insert_resource(Interpolation::new(Duration::from_millis(
+        args.interpolation_delay_ms,
+    )));
+    commands.spawn((
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(12.),
+            right: Val::Px(12.),
+            ..default()
+        }),
+        DebugText,
+    ));
+}
+
+fn interpolate(
+    mut interpolation: ResMut<Interpolation>,
+    current_role: Res<CurrentRole>,
+    mut hoops_and_balls: ParamSet<(HoopQuery, BallQuery)>,
+) {
+    let Some(time) = interpolation.render_time() else {
+        return;
+    };
+    // The player's own hoop is predicted instead.
+    if !matches!(current_role.0, Role::Hoop) {
+        if let Some(position) = interpolation.hoop.position_at(time) {
+            let x = position.x.clamp(HOOP_MIN_X, HOOP_MAX_X);
+            move_hoop(&mut hoops_and_balls.p0(), x);
+        }
+    }
+    for (&id, samples) in &mut interpolation.balls {
+        if let Some(position) = samples.position_at(time) {
+            let position = Point {
+                x: position.x,
+                y: position.y,
+            };
+            move_ball(id, position, &mut hoops_and_balls.p1());
+        }
+    }
+}
+
+/// F3 shows how far behind the server things are shown, and how much is buffered for each.
+fn show_debug(
+    mut interpolation: ResMut<Interpolation>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut text: Query<&mut Text, With<DebugText>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        interpolation.show_debug = !interpolation.show_debug;
+    }
+    let mut lines = vec![];
+    if let (true, Some(time)) = (interpolation.show_debug, interpolation.render_time()) {
+        lines.push(format!(
+            "Delay {:.0}ms, server tick {:.0}",
+            interpolation.delay * 1000.,
+            interpolation.tick_time / TICK_SECONDS as f64
+        ));
+        let describe = |samples: &Samples| {
+            format!(
+                "{} buffered{}",
+                samples.buffered(time),
+                if samples.extrapolating(time) {
+                    ", extrapolating"
+                } else {
+                    ""
+                }
+            )
+        };
+        lines.push(format!("Hoop: {}", describe(&interpolation.hoop)));
+        let mut balls: Vec<_> = interpolation.balls.iter().collect();
+        balls.sort_by_key(|(&id, _)| id);
+        for (id, samples) in balls {
+            lines.push(format!("Ball {id}: {}", describe(samples)));
+        }
+    }
+    text.single_mut().sections[0].value = lines.join("\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: f64 = TICK_SECONDS as f64;
+
+    #[test]
+    fn moving_hoop_is_extrapolated() {
+        let mut samples = Samples::default();
+        samples.push(0., Vec2::new(100., 0.), false);
+        samples.push(TICK, Vec2::new(101., 0.), false);
+        let ahead = samples.position_at(2. * TICK).unwrap();
+        assert!((ahead.x - 102.).abs() < 1e-3, "{ahead}");
+    }
+
+    #[test]
+    fn stopped_hoop_stays_put() {
+        let mut samples = Samples::default();
+        samples.push(0., Vec2::new(100., 0.), false);
+        samples.push(TICK, Vec2::new(101., 0.), false);
+        // The next tick came without a move.
+        samples.hold(2. * TICK);
+        assert_eq!(samples.position_at(1.), Some(Vec2::new(101., 0.)));
+        assert!(!samples.extrapolating(1.));
+        // And it starts off again from there.
+        samples.push(3. * TICK, Vec2::new(102., 0.), false);
+        let ahead = samples.position_at(4. * TICK).unwrap();
+        assert!((ahead.x - 103.).abs() < 1e-3, "{ahead}");
+    }
+}
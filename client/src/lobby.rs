@@ -0,0 +1,261 @@
+use bevy::{
+    input::keyboard::{Key, KeyboardInput},
+    prelude::*,
+};
+use nope_the_hoop_proto::{
+    lobby::{GameInfo, GameRef},
+    message::ToServerMessage,
+};
+
+use crate::connection::ServerConnection;
+
+/// Something the player is typing in the lobby.
+enum Prompt {
+    NewGameName {
+        name: String,
+        private: bool,
+    },
+    NewGamePassword {
+        name: String,
+        private: bool,
+        password: String,
+    },
+    JoinCode {
+        code: String,
+    },
+    Password {
+        game: GameRef,
+        password: String,
+    },
+}
+
+impl Prompt {
+    fn text(&mut self) -> &mut String {
+        match self {
+            Prompt::NewGameName { name, .. } => name,
+            Prompt::NewGamePassword { password, .. } | Prompt::Password { password, .. } => {
+                password
+            }
+            Prompt::JoinCode { code } => code,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Prompt::NewGameName { name, private } => format!(
+                "New {} game name (Tab: make {}, Enter: next, Esc: cancel): {name}_",
+                if *private { "private" } else { "public" },
+                if *private { "public" } else { "private" },
+            ),
+            Prompt::NewGamePassword { password, .. } => format!(
+                "Password for the new game, if any (Enter: create, Esc: cancel): {}_",
+                "*".repeat(password.chars().count())
+            ),
+            Prompt::JoinCode { code } => {
+                format!("Join code (Enter: join, Esc: cancel): {code}_")
+            }
+            Prompt::Password { game, password } => format!(
+                "Password for game {game} (Enter: join, Esc: cancel): {}_",
+                "*".repeat(password.chars().count())
+            ),
+        }
+    }
+}
+
+/// The games on the server, and what the player is doing with them.
+#[derive(Resource, Default)]
+pub struct Lobby {
+    games: Vec<GameInfo>,
+    selected: usize,
+    prompt: Option<Prompt>,
+    /// The last thing that went wrong, to show the player.
+    error: Option<String>,
+    visible: bool,
+}
+
+impl Lobby {
+    pub fn show(&mut self) {
+        self.visible = true;
+    }
+
+    pub fn hide(&mut self) {
+        self.visible = false;
+        self.error = None;
+        self.prompt = None;
+    }
+
+    pub fn set_games(&mut self, games: Vec<GameInfo>) {
+        self.games = games;
+        self.selected = self.selected.min(self.games.len().saturating_sub(1));
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// Asks the player for the password of a game they tried to join.
+    pub fn ask_password(&mut self, game: GameRef) {
+        self.prompt = Some(Prompt::Password {
+            game,
+            password: String::new(),
+        });
+    }
+}
+
+#[derive(Component)]
+struct LobbyText;
+
+pub fn setup(app: &mut App) {
+    app.init_resource::<Lobby>()
+        .add_systems(Startup, setup_lobby_text)
+        .add_systems(Update, (handle_input, show_lobby).chain());
+}
+
+fn setup_lobby_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(12.),
+            left: Val::Px(12.),
+            ..default()
+        }),
+        LobbyText,
+    ));
+}
+
+fn handle_input(
+    mut lobby: ResMut<Lobby>,
+    mut server: ResMut<ServerConnection>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut typed: EventReader<KeyboardInput>,
+) {
+    if !lobby.visible {
+        typed.clear();
+        return;
+    }
+    if let Some(prompt) = &mut lobby.prompt {
+        for key in typed.read() {
+            if !key.state.is_pressed() {
+                continue;
+            }
+            match &key.logical_key {
+                Key::Character(c) => prompt.text().push_str(c),
+                Key::Space => prompt.text().push(' '),
+                Key::Backspace => _ = prompt.text().pop(),
+                Key::Tab => {
+                    if let Prompt::NewGameName { private, .. } = prompt {
+                        *private = !*private;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if keyboard_input.just_pressed(KeyCode::Enter) {
+            let prompt = lobby.prompt.take().unwrap();
+            lobby.prompt = submit(prompt, &mut server);
+        } else if keyboard_input.just_pressed(KeyCode::Escape) {
+            lobby.prompt = None;
+        }
+        return;
+    }
+    typed.clear();
+    if keyboard_input.just_pressed(KeyCode::ArrowUp) {
+        lobby.selected = lobby.selected.saturating_sub(1);
+    }
+    if keyboard_input.just_pressed(KeyCode::ArrowDown) {
+        lobby.selected = (lobby.selected + 1).min(lobby.games.len().saturating_sub(1));
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyR) {
+        server.send(ToServerMessage::ListGames);
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyN) {
+        lobby.prompt = Some(Prompt::NewGameName {
+            name: String::new(),
+            private: false,
+        });
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyC) {
+        lobby.prompt = Some(Prompt::JoinCode {
+            code: String::new(),
+        });
+    }
+    if keyboard_input.just_pressed(KeyCode::Enter) {
+        if let Some(game) = lobby.games.get(lobby.selected) {
+            let game_ref = GameRef::Id(game.id);
+            if game.password_required {
+                lobby.ask_password(game_ref);
+            } else {
+                server.join(game_ref, None);
+            }
+        }
+    }
+}
+
+/// Acts on what the player typed, returning the next prompt if there is one.
+fn submit(prompt: Prompt, server: &mut ServerConnection) -> Option<Prompt> {
+    match prompt {
+        Prompt::NewGameName { name, private } => Some(Prompt::NewGamePassword {
+            name,
+            private,
+            password: String::new(),
+        }),
+        Prompt::NewGamePassword {
+            name,
+            private,
+            password,
+        } => {
+            let password = (!password.is_empty()).then_some(password);
+            server.create_game(name, private, password);
+            None
+        }
+        Prompt::JoinCode { code } => {
+            server.join(GameRef::Code(code.trim().to_owned()), None);
+            None
+        }
+        Prompt::Password { game, password } => {
+            server.join(game, Some(password));
+            None
+        }
+    }
+}
+
+fn show_lobby(lobby: Res<Lobby>, mut text: Query<(&mut Text, &mut Visibility), With<LobbyText>>) {
+    if !lobby.is_changed() {
+        return;
+    }
+    let (mut text, mut visibility) = text.single_mut();
+    *visibility = if lobby.visible {
+        Visibility::Visible
+    } else {
+        Visibility::Hidden
+    };
+    let mut lines = vec![
+        "Games (Up/Down: select, Enter: join, C: join by code, N: new game, R: refresh)".to_owned(),
+    ];
+    if lobby.games.is_empty() {
+        lines.push("  No games yet".to_owned());
+    }
+    for (index, game) in lobby.games.iter().enumerate() {
+        let marker = if index == lobby.selected { '>' } else { ' ' };
+        lines.push(format!(
+            "{marker} {} [{}]{} - {} playing, hoop {}, {} ball slots free",
+            game.name,
+            game.code,
+            if game.password_required {
+                " (password)"
+            } else {
+                ""
+            },
+            game.players,
+            if game.hoop_free { "free" } else { "taken" },
+            game.ball_slots_free,
+        ));
+    }
+    if let Some(prompt) = &lobby.prompt {
+        lines.push(prompt.describe());
+    }
+    if let Some(error) = &lobby.error {
+        lines.push(error.clone());
+    }
+    text.sections[0].value = lines.join("\n");
+}
@@ -1,11 +1,16 @@
 mod ball;
 mod connection;
 mod hoop;
+mod hud;
+mod interpolation;
+mod lobby;
+mod swap;
 
-use std::fmt::Display;
+use std::{fmt::Display, path::PathBuf};
 
 use bevy::prelude::*;
 use clap::Parser;
+use nope_the_hoop_proto::{lobby::GameRef, message::PreferredRole, tls::CertificatePin};
 
 #[derive(Parser)]
 #[command(
@@ -18,9 +23,56 @@ struct Args {
     #[arg(short = 'p', long, default_value_t = 7434)]
     port: u16,
 
+    /// How to reach the server. UDP needs the server started with `--udp-port`, given here as
+    /// `--port`.
+    #[arg(long, value_enum, default_value_t = Transport::Tcp)]
+    transport: Transport,
+
+    /// Connect over TLS, trusting certificates from the usual public authorities unless
+    /// `--tls-ca` or `--tls-pin` says otherwise. Needs the server started with `--tls-cert`.
+    #[arg(long)]
+    tls: bool,
+
+    /// A PEM file with the certificate authority to trust the server's certificate by instead,
+    /// as for a self-hosted server.
+    #[arg(long, requires = "tls", conflicts_with = "tls_pin")]
+    tls_ca: Option<PathBuf>,
+
+    /// The SHA-256 hash of the one certificate to trust, as the server logs it at startup.
+    #[arg(long, requires = "tls")]
+    tls_pin: Option<CertificatePin>,
+
     /// The server address to connect to.
     #[arg(short, long, default_value = "127.0.0.1")]
     server: String,
+
+    /// The passphrase to answer the server's authentication challenge with.
+    #[arg(long)]
+    passphrase: Option<String>,
+
+    /// The game to join, by id or join code. Without it the client starts in the lobby.
+    #[arg(short, long)]
+    game: Option<GameRef>,
+
+    /// The password of the game given with `--game`, if it has one.
+    #[arg(long, requires = "game")]
+    game_password: Option<String>,
+
+    /// The role to take in the game if it's free: hoop, ball or observer.
+    #[arg(short, long)]
+    role: Option<PreferredRole>,
+
+    /// How far behind the server, in milliseconds, other players' hoop and balls are shown, to
+    /// smooth over updates arriving unevenly.
+    #[arg(long, default_value_t = 100)]
+    interpolation_delay_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum Transport {
+    Tcp,
+    /// Lets the hoop and balls skip lost moves instead of waiting on them to be resent.
+    Udp,
 }
 
 enum Role {
@@ -65,6 +117,10 @@ fn main() {
     connection::setup(&mut app);
     ball::setup(&mut app);
     hoop::setup(&mut app);
+    hud::setup(&mut app);
+    interpolation::setup(&mut app);
+    lobby::setup(&mut app);
+    swap::setup(&mut app);
     app.run();
 }
 
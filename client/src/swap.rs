@@ -0,0 +1,100 @@
+use bevy::prelude::*;
+use nope_the_hoop_proto::message::ToServerMessage;
+
+use crate::{connection::ServerConnection, CurrentRole, Role};
+
+/// Role swap requests waiting on the hoop, and how the player's own request went.
+#[derive(Resource, Default)]
+pub struct RoleSwaps {
+    /// Balls whose players asked the hoop to swap, oldest first.
+    requests: Vec<u32>,
+    status: Option<String>,
+}
+
+impl RoleSwaps {
+    pub fn requested(&mut self, ball_id: u32) {
+        if !self.requests.contains(&ball_id) {
+            self.requests.push(ball_id);
+        }
+    }
+
+    pub fn set_status(&mut self, status: String) {
+        self.status = Some(status);
+    }
+
+    /// Forgets requests that were meant for the player's old role.
+    pub fn role_changed(&mut self) {
+        self.requests.clear();
+    }
+}
+
+#[derive(Component)]
+struct SwapText;
+
+pub fn setup(app: &mut App) {
+    app.init_resource::<RoleSwaps>()
+        .add_systems(Startup, setup_swap_text)
+        .add_systems(Update, (handle_input, show_swaps).chain());
+}
+
+fn setup_swap_text(mut commands: Commands) {
+    commands.spawn((
+        TextBundle::from_section("", TextStyle::default()).with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(12.),
+            left: Val::Px(12.),
+            ..default()
+        }),
+        SwapText,
+    ));
+}
+
+fn handle_input(
+    mut swaps: ResMut<RoleSwaps>,
+    mut server: ResMut<ServerConnection>,
+    current_role: Res<CurrentRole>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+) {
+    match current_role.0 {
+        Role::Ball { .. } if keyboard_input.just_pressed(KeyCode::KeyH) => {
+            server.send(ToServerMessage::RequestRoleSwap);
+            swaps.set_status("Asked the hoop to swap".to_owned());
+        }
+        Role::Hoop if !swaps.requests.is_empty() => {
+            let accept = if keyboard_input.just_pressed(KeyCode::KeyY) {
+                true
+            } else if keyboard_input.just_pressed(KeyCode::KeyN) {
+                false
+            } else {
+                return;
+            };
+            let ball_id = swaps.requests.remove(0);
+            server.send(ToServerMessage::AnswerRoleSwap { ball_id, accept });
+        }
+        _ => {}
+    }
+}
+
+fn show_swaps(
+    swaps: Res<RoleSwaps>,
+    current_role: Res<CurrentRole>,
+    mut text: Query<&mut Text, With<SwapText>>,
+) {
+    if !swaps.is_changed() && !current_role.is_changed() {
+        return;
+    }
+    let mut lines = vec![];
+    match current_role.0 {
+        Role::Ball { .. } => lines.push("H: ask for the hoop".to_owned()),
+        Role::Hoop => {
+            if let Some(ball_id) = swaps.requests.first() {
+                lines.push(format!(
+                    "Ball {ball_id} wants the hoop next round (Y: accept, N: decline)"
+                ));
+            }
+        }
+        Role::Unknown | Role::Observer => {}
+    }
+    lines.extend(swaps.status.clone());
+    text.single_mut().sections[0].value = lines.join("\n");
+}
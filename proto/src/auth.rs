@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const NONCE_LEN: usize = 32;
+pub const DIGEST_LEN: usize = 32;
+pub const SALT_LEN: usize = 16;
+
+pub type Nonce = [u8; NONCE_LEN];
+pub type Salt = [u8; SALT_LEN];
+
+/// The SHA-256 hash of a passphrase. This is all the server keeps in memory, and what the client
+/// mixes with the server's nonce to answer a challenge. It's only sent over the wire to set a
+/// game's password, and then salted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassphraseHash([u8; DIGEST_LEN]);
+
+/// Identifies a client's place in a game, so that it can get its role back after reconnecting.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionToken(pub [u8; 16]);
+
+/// A game's password as its creator sets it. The hash is salted for the game, so it opens no other
+/// game with the same password, but it does open this one: anyone who sees it go by can join.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GamePassword {
+    pub salt: Salt,
+    pub hash: PassphraseHash,
+}
+
+impl GamePassword {
+    pub fn new(password: &str, salt: Salt) -> Self {
+        Self {
+            salt,
+            hash: PassphraseHash::salted(password, &salt),
+        }
+    }
+}
+
+/// A client's answer to an authentication challenge.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChallengeResponse([u8; DIGEST_LEN]);
+
+impl PassphraseHash {
+    pub fn new(passphrase: &str) -> Self {
+        Self(Sha256::digest(passphrase.as_bytes()).into())
+    }
+
+    /// Hashes a game password with the game's salt.
+    pub fn salted(passphrase: &str, salt: &Salt) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(passphrase.as_bytes());
+        Self(hasher.finalize().into())
+    }
+
+    pub fn respond(&self, nonce: &Nonce) -> ChallengeResponse {
+        let mut hasher = Sha256::new();
+        hasher.update(nonce);
+        hasher.update(self.0);
+        ChallengeResponse(hasher.finalize().into())
+    }
+
+    /// Checks a client's response in constant time.
+    pub fn verify(&self, nonce: &Nonce, response: &ChallengeResponse) -> bool {
+        let expected = self.respond(nonce);
+        expected
+            .0
+            .iter()
+            .zip(response.0.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let hash = PassphraseHash::new("open sesame");
+        let nonce = [7u8; NONCE_LEN];
+        let response = hash.respond(&nonce);
+        assert!(hash.verify(&nonce, &response));
+        assert!(!hash.verify(&[8u8; NONCE_LEN], &response));
+        assert!(!PassphraseHash::new("open sesame!").verify(&nonce, &response));
+    }
+
+    #[test]
+    fn salts_differ() {
+        let password = GamePassword::new("open sesame", [1; SALT_LEN]);
+        let nonce = [7u8; NONCE_LEN];
+        let response = password.hash.respond(&nonce);
+        let other_game = GamePassword::new("open sesame", [2; SALT_LEN]);
+        assert!(!other_game.hash.verify(&nonce, &response));
+        assert!(!PassphraseHash::new("open sesame").verify(&nonce, &response));
+    }
+}
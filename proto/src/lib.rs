@@ -1,8 +1,27 @@
+pub mod auth;
+pub mod lobby;
 pub mod message;
 pub mod state;
 #[cfg(feature = "async")]
 pub mod stream;
 pub mod sync;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod udp;
+#[cfg(feature = "async")]
+pub mod udp_stream;
 
 pub(crate) type LenType = u16;
-pub(crate) const MAX_MESSAGE_SIZE: usize = 512;
+/// Big enough for a tick's updates or a full snapshot with every ball in the game.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// The length prefix for a message of `len` bytes, refusing one too long for the other side to
+/// read.
+pub(crate) fn message_len(len: usize) -> anyhow::Result<LenType> {
+    if len > MAX_MESSAGE_SIZE {
+        anyhow::bail!("Message too long: {len}");
+    }
+    Ok(len as LenType)
+}
@@ -0,0 +1,77 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Salt;
+
+/// A way to name a game to join.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum GameRef {
+    Id(u32),
+    Code(String),
+}
+
+impl FromStr for GameRef {
+    type Err = std::convert::Infallible;
+
+    /// Numbers are ids, anything else is a join code.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(id) => GameRef::Id(id),
+            Err(_) => GameRef::Code(s.to_owned()),
+        })
+    }
+}
+
+impl Display for GameRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameRef::Id(id) => write!(f, "{id}"),
+            GameRef::Code(code) => write!(f, "{code}"),
+        }
+    }
+}
+
+/// What the lobby shows about a game.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GameInfo {
+    pub id: u32,
+    pub name: String,
+    pub code: String,
+    pub players: u32,
+    pub hoop_free: bool,
+    pub ball_slots_free: u32,
+    /// Private games are left out of the list, and can only be joined by code.
+    pub private: bool,
+    pub password_required: bool,
+}
+
+/// Why a lobby request failed. Unlike a rejection, the client stays in the lobby.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum LobbyError {
+    NoSuchGame {
+        game: GameRef,
+    },
+    InvalidName,
+    /// The server is running as many games as it will, so no more can be created for now.
+    TooManyGames,
+    /// With the game's salt, for clients that joined by code to hash the password with and try
+    /// again.
+    WrongGamePassword {
+        game: GameRef,
+        salt: Salt,
+    },
+}
+
+impl Display for LobbyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LobbyError::NoSuchGame { game } => write!(f, "there's no game {game}"),
+            LobbyError::InvalidName => write!(f, "game names must be 1 to 32 characters"),
+            LobbyError::TooManyGames => write!(f, "the server can't take any more games"),
+            LobbyError::WrongGamePassword { game, .. } => {
+                write!(f, "wrong or missing password for game {game}")
+            }
+        }
+    }
+}
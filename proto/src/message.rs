@@ -1,34 +1,308 @@
-use serde::{Deserialize, Serialize};
-
-use crate::state::{self};
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub enum HorizontalDirection {
-    Left,
-    Right,
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub enum ToClientMessage {
-    InitialState(state::GameState),
-    EstablishAsHoop,
-    EstablishAsBall { id: u32 },
-    EstablishAsObserver,
-    UpdateState(state::UpdateState),
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub enum ToServerMessage {
-    Hello {
-        game_id: u32,
-    },
-    MoveHoop {
-        direction: HorizontalDirection,
-        seconds_pressed: f32,
-    },
-    ShootBall {
-        id: u32,
-        angle: f32,
-        seconds_pressed: f32,
-    },
-}
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{ChallengeResponse, GamePassword, Nonce, SessionToken},
+    lobby::{GameInfo, GameRef, LobbyError},
+    state::{MatchPhase, MatchWinner, Standing, StateDelta, UpdateState},
+};
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u32 = 17;
+/// The oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 17;
+pub const SUPPORTED_PROTOCOL_VERSIONS: VersionRange = VersionRange {
+    min: MIN_PROTOCOL_VERSION,
+    max: PROTOCOL_VERSION,
+};
+
+/// Capabilities are optional protocol features, exchanged by name so peers can ignore ones they
+/// don't know about.
+pub const CAPABILITY_PASSPHRASE_AUTH: &str = "passphrase-auth";
+pub const CAPABILITIES: &[&str] = &[CAPABILITY_PASSPHRASE_AUTH];
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    pub fn contains(&self, version: u32) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+
+    /// Picks the version to speak with a peer whose newest version is `peer_version`, if any.
+    pub fn negotiate(&self, peer_version: u32) -> Option<u32> {
+        let version = peer_version.min(self.max);
+        self.contains(version).then_some(version)
+    }
+}
+
+impl Display for VersionRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..={}", self.min, self.max)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum HorizontalDirection {
+    Left,
+    Right,
+}
+
+/// A role a client can ask for when joining. Which ball it gets is up to the server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PreferredRole {
+    Hoop,
+    Ball,
+    Observer,
+}
+
+impl FromStr for PreferredRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hoop" => Ok(PreferredRole::Hoop),
+            "ball" => Ok(PreferredRole::Ball),
+            "observer" => Ok(PreferredRole::Observer),
+            _ => Err(format!(
+                "unknown role {s:?} (expected hoop, ball or observer)"
+            )),
+        }
+    }
+}
+
+/// Why the server refused a client during the hello handshake.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum RejectReason {
+    WrongPassphrase,
+    UnsupportedProtocolVersion {
+        requested: u32,
+        supported: VersionRange,
+    },
+    MissingCapability {
+        capability: String,
+    },
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::WrongPassphrase => write!(f, "wrong passphrase"),
+            RejectReason::UnsupportedProtocolVersion {
+                requested,
+                supported,
+            } => write!(
+                f,
+                "unsupported protocol version {requested} (server supports {supported})"
+            ),
+            RejectReason::MissingCapability { capability } => {
+                write!(f, "client lacks required capability {capability:?}")
+            }
+        }
+    }
+}
+
+/// Why the server ignored a command from a client.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum CommandError {
+    NotTheHoop,
+    NotYourBall { id: u32 },
+    InvalidInput,
+    BallInFlight { id: u32 },
+    NotABall,
+    NoSwapRequested { ball_id: u32 },
+    NotPlaying,
+    OutOfShots { id: u32 },
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotTheHoop => write!(f, "only the hoop can move the hoop"),
+            CommandError::NotYourBall { id } => write!(f, "ball {id} isn't yours to shoot"),
+            CommandError::InvalidInput => write!(f, "input had an invalid number"),
+            CommandError::BallInFlight { id } => write!(f, "ball {id} is already in flight"),
+            CommandError::NotABall => write!(f, "only ball players can ask for the hoop"),
+            CommandError::NoSwapRequested { ball_id } => {
+                write!(f, "ball {ball_id} didn't ask for the hoop")
+            }
+            CommandError::NotPlaying => write!(f, "balls can only be shot while a round is on"),
+            CommandError::OutOfShots { id } => {
+                write!(f, "ball {id} has no shots left this round")
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ToClientMessage {
+    HelloAccepted {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+        /// What to answer game password challenges with on this connection.
+        password_nonce: Nonce,
+    },
+    AuthChallenge {
+        nonce: Nonce,
+    },
+    Rejected {
+        reason: RejectReason,
+    },
+    GameList {
+        games: Vec<GameInfo>,
+    },
+    GameCreated {
+        game: GameInfo,
+    },
+    LobbyError {
+        error: LobbyError,
+    },
+    EstablishAsHoop {
+        session: SessionToken,
+    },
+    EstablishAsBall {
+        id: u32,
+        session: SessionToken,
+    },
+    EstablishAsObserver {
+        session: SessionToken,
+    },
+    /// How the hoop and balls moved in tick `tick`, sent every tick even if nothing did. A newer
+    /// tick makes up for a lost one, as the objects are shown moving between the positions that
+    /// arrive.
+    Tick {
+        tick: u64,
+        updates: Vec<UpdateState>,
+    },
+    /// Everything else that happened in tick `tick`, such as balls coming and going and scores,
+    /// sent after the tick if there was anything. These aren't made up for by later ones.
+    Events {
+        tick: u64,
+        updates: Vec<UpdateState>,
+    },
+    /// The whole game state as of tick `tick`, sent every so often so that clients that missed or
+    /// misapplied an update catch up. It's given as the changes since the `baseline` snapshot the
+    /// client last acknowledged, or since an empty game if there's none.
+    Snapshot {
+        tick: u64,
+        baseline: Option<u64>,
+        delta: StateDelta,
+        /// The last of the receiver's own hoop moves the state includes, as for
+        /// [`UpdateState::MoveHoop`]. Zero for clients that haven't moved the hoop.
+        hoop_sequence: u32,
+    },
+    CommandRejected {
+        error: CommandError,
+    },
+    /// Sent to the hoop when a ball player asks to trade roles with it.
+    RoleSwapRequested {
+        ball_id: u32,
+    },
+    /// Sent to a ball player once the hoop answers its swap request.
+    RoleSwapAnswered {
+        accepted: bool,
+    },
+    /// The player of ball `ball_id` became the hoop, and the old hoop (if any) took the ball. The
+    /// players whose roles changed are also sent their new role.
+    RolesSwapped {
+        ball_id: u32,
+    },
+    /// The match moved on to `phase`, which ends in `remaining_secs` if it's timed.
+    PhaseChanged {
+        phase: MatchPhase,
+        rounds: u32,
+        remaining_secs: Option<f32>,
+    },
+    /// Sent as the match ends, with the balls from best to worst.
+    MatchOver {
+        standings: Vec<Standing>,
+        hoop_nopes: u32,
+        winner: MatchWinner,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ToServerMessage {
+    /// The version and capabilities default to empty so that clients from before they were added
+    /// still parse, and get a readable rejection.
+    Hello {
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    AuthResponse {
+        response: ChallengeResponse,
+    },
+    ListGames,
+    CreateGame {
+        name: String,
+        private: bool,
+        password: Option<GamePassword>,
+    },
+    JoinGame {
+        game: GameRef,
+        /// The session from an earlier connection to the game, to get back the same role.
+        session: Option<SessionToken>,
+        /// The game's salted password mixed with the connection's password nonce, if it has one.
+        password: Option<ChallengeResponse>,
+        /// The role to take if it's free. Clients resuming a session get their old role instead.
+        preferred_role: Option<PreferredRole>,
+    },
+    MoveHoop {
+        direction: HorizontalDirection,
+        seconds_pressed: f32,
+        /// Counts up with every move, and comes back in the `MoveHoop` update that includes it.
+        sequence: u32,
+    },
+    ShootBall {
+        id: u32,
+        angle: f32,
+        seconds_pressed: f32,
+    },
+    /// Says that the snapshot for tick `tick` arrived, so later ones can be sent relative to it.
+    AckSnapshot {
+        tick: u64,
+    },
+    /// Asks to trade roles with the hoop at the end of the round.
+    RequestRoleSwap,
+    AnswerRoleSwap {
+        ball_id: u32,
+        accept: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_version() {
+        let range = VersionRange { min: 2, max: 4 };
+        assert_eq!(range.negotiate(3), Some(3));
+        assert_eq!(range.negotiate(7), Some(4));
+        assert_eq!(range.negotiate(1), None);
+    }
+
+    #[test]
+    fn legacy_hello() {
+        #[derive(Serialize)]
+        enum LegacyToServerMessage {
+            Hello { game_id: u32 },
+        }
+        let mut buf = vec![];
+        ciborium::ser::into_writer(&LegacyToServerMessage::Hello { game_id: 5 }, &mut buf).unwrap();
+        let message: ToServerMessage = ciborium::from_reader(&buf[..]).unwrap();
+        assert_eq!(
+            message,
+            ToServerMessage::Hello {
+                protocol_version: 0,
+                capabilities: vec![],
+            }
+        );
+    }
+}
@@ -1,42 +1,261 @@
-use std::collections::HashMap;
-
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
-pub struct Point {
-    pub x: f32,
-    pub y: f32,
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
-pub struct GameState {
-    pub hoop_x: f32,
-    pub ball_positions: HashMap<u32, Point>,
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
-pub enum UpdateState {
-    MoveHoop { x: f32 },
-    AddBall { id: u32, position: Point },
-    MoveBall { id: u32, position: Point },
-    RemoveBall { id: u32 },
-}
-
-impl UpdateState {
-    pub fn apply(&self, state: &mut GameState) {
-        match self {
-            UpdateState::MoveHoop { x } => {
-                state.hoop_x = *x;
-            }
-            UpdateState::AddBall { id, position } => {
-                let _previous = state.ball_positions.insert(*id, *position);
-            }
-            UpdateState::MoveBall { id, position } => {
-                let _previous = state.ball_positions.insert(*id, *position);
-            }
-            UpdateState::RemoveBall { id } => {
-                let _previous = state.ball_positions.remove(id);
-            }
-        }
-    }
-}
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct GameState {
+    pub hoop_x: f32,
+    pub ball_positions: HashMap<u32, Point>,
+    /// How many times each ball's shooter scored, by ball id.
+    pub scores: HashMap<u32, u32>,
+    /// What the hoop's player scored as a ball earlier in the match, kept for when they shoot
+    /// again.
+    pub hoop_score: u32,
+    /// How many shots the hoop noped.
+    pub nopes: u32,
+}
+
+/// What changed between two game states, to send a snapshot as only what the receiver doesn't
+/// already have.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct StateDelta {
+    pub hoop_x: Option<f32>,
+    /// Balls that were added or moved.
+    pub ball_positions: HashMap<u32, Point>,
+    pub removed_balls: Vec<u32>,
+    pub scores: HashMap<u32, u32>,
+    pub removed_scores: Vec<u32>,
+    pub hoop_score: Option<u32>,
+    pub nopes: Option<u32>,
+}
+
+impl StateDelta {
+    pub fn between(old: &GameState, new: &GameState) -> Self {
+        Self {
+            hoop_x: (old.hoop_x != new.hoop_x).then_some(new.hoop_x),
+            ball_positions: changed(&old.ball_positions, &new.ball_positions),
+            removed_balls: removed(&old.ball_positions, &new.ball_positions),
+            scores: changed(&old.scores, &new.scores),
+            removed_scores: removed(&old.scores, &new.scores),
+            hoop_score: (old.hoop_score != new.hoop_score).then_some(new.hoop_score),
+            nopes: (old.nopes != new.nopes).then_some(new.nopes),
+        }
+    }
+
+    /// The whole of `state`, for a receiver that has nothing to go on.
+    pub fn full(state: &GameState) -> Self {
+        Self::between(&GameState::default(), state)
+    }
+
+    pub fn apply(&self, state: &mut GameState) {
+        if let Some(hoop_x) = self.hoop_x {
+            state.hoop_x = hoop_x;
+        }
+        state.ball_positions.extend(&self.ball_positions);
+        for id in &self.removed_balls {
+            let _previous = state.ball_positions.remove(id);
+        }
+        state.scores.extend(&self.scores);
+        for id in &self.removed_scores {
+            let _previous = state.scores.remove(id);
+        }
+        if let Some(hoop_score) = self.hoop_score {
+            state.hoop_score = hoop_score;
+        }
+        if let Some(nopes) = self.nopes {
+            state.nopes = nopes;
+        }
+    }
+}
+
+fn changed<V: PartialEq + Copy>(old: &HashMap<u32, V>, new: &HashMap<u32, V>) -> HashMap<u32, V> {
+    new.iter()
+        .filter(|&(id, value)| old.get(id) != Some(value))
+        .map(|(&id, &value)| (id, value))
+        .collect()
+}
+
+fn removed<V>(old: &HashMap<u32, V>, new: &HashMap<u32, V>) -> Vec<u32> {
+    old.keys()
+        .filter(|id| !new.contains_key(id))
+        .copied()
+        .collect()
+}
+
+/// Where a match is at. Rounds are numbered from 1.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MatchPhase {
+    /// There isn't both a hoop and a ball player yet.
+    WaitingForPlayers,
+    Countdown {
+        round: u32,
+    },
+    Playing {
+        round: u32,
+    },
+    RoundOver {
+        round: u32,
+    },
+    MatchOver,
+}
+
+/// A player's final score in a match.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Standing {
+    /// The player's ball, or `None` for the hoop's player, who scored in earlier rounds.
+    pub ball_id: Option<u32>,
+    pub score: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MatchWinner {
+    /// The player with the top score, named as in their `Standing`.
+    Player { ball_id: Option<u32> },
+    /// Nobody scored.
+    Hoop,
+    /// More than one ball had the top score.
+    Draw,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum UpdateState {
+    MoveHoop {
+        x: f32,
+        /// The last of the hoop player's moves that `x` includes, so it can replay the later ones.
+        sequence: u32,
+    },
+    AddBall {
+        id: u32,
+        position: Point,
+    },
+    MoveBall {
+        id: u32,
+        position: Point,
+    },
+    RemoveBall {
+        id: u32,
+    },
+    /// The ball's shot is over, and it's back at `position` waiting to be shot again.
+    ResetBall {
+        id: u32,
+        position: Point,
+    },
+    /// The ball went through the hoop, bringing its shooter's score to `score`.
+    Scored {
+        id: u32,
+        score: u32,
+    },
+    /// The hoop blocked the ball, bringing the hoop's nopes to `nopes`.
+    Noped {
+        id: u32,
+        nopes: u32,
+    },
+    /// A new match started, so everyone is back to nothing.
+    ScoresReset,
+    /// The ball's player took the hoop and the hoop's player took the ball, each keeping their
+    /// own score: the ball's goes to the hoop, and the hoop's to the ball.
+    ScoresSwapped {
+        id: u32,
+    },
+    /// The hoop's player left for good, so their score isn't anyone's.
+    HoopScoreCleared,
+}
+
+impl UpdateState {
+    /// Whether it just moves something along, which goes in a `Tick` rather than with the events.
+    pub fn is_move(&self) -> bool {
+        matches!(
+            self,
+            UpdateState::MoveHoop { .. } | UpdateState::MoveBall { .. }
+        )
+    }
+
+    pub fn apply(&self, state: &mut GameState) {
+        match self {
+            UpdateState::MoveHoop { x, .. } => {
+                state.hoop_x = *x;
+            }
+            UpdateState::AddBall { id, position } => {
+                let _previous = state.ball_positions.insert(*id, *position);
+            }
+            UpdateState::MoveBall { id, position } | UpdateState::ResetBall { id, position } => {
+                let _previous = state.ball_positions.insert(*id, *position);
+            }
+            UpdateState::RemoveBall { id } => {
+                let _previous = state.ball_positions.remove(id);
+            }
+            UpdateState::Scored { id, score } => {
+                let _previous = state.scores.insert(*id, *score);
+            }
+            UpdateState::Noped { id: _, nopes } => {
+                state.nopes = *nopes;
+            }
+            UpdateState::ScoresReset => {
+                state.scores.clear();
+                state.hoop_score = 0;
+                state.nopes = 0;
+            }
+            UpdateState::ScoresSwapped { id } => {
+                let ball_score = state.scores.insert(*id, state.hoop_score);
+                state.hoop_score = ball_score.unwrap_or(0);
+            }
+            UpdateState::HoopScoreCleared => {
+                state.hoop_score = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(hoop_x: f32, balls: &[(u32, f32)], scores: &[(u32, u32)], nopes: u32) -> GameState {
+        GameState {
+            hoop_x,
+            ball_positions: balls
+                .iter()
+                .map(|&(id, x)| (id, Point { x, y: 0. }))
+                .collect(),
+            scores: scores.iter().copied().collect(),
+            hoop_score: 0,
+            nopes,
+        }
+    }
+
+    #[test]
+    fn deltas() {
+        let old = state(10., &[(0, 1.), (1, 2.), (2, 3.)], &[(0, 1), (1, 1)], 2);
+        let new = state(10., &[(0, 1.), (1, 5.), (3, 4.)], &[(0, 2)], 2);
+        let delta = StateDelta::between(&old, &new);
+        assert_eq!(delta.hoop_x, None);
+        assert_eq!(delta.nopes, None);
+        assert_eq!(delta.ball_positions.len(), 2);
+        assert_eq!(delta.removed_balls, [2]);
+        assert_eq!(delta.removed_scores, [1]);
+        let mut applied = old.clone();
+        delta.apply(&mut applied);
+        assert_eq!(applied, new);
+
+        let mut applied = GameState::default();
+        StateDelta::full(&new).apply(&mut applied);
+        assert_eq!(applied, new);
+        assert_eq!(StateDelta::between(&new, &new), StateDelta::default());
+    }
+
+    #[test]
+    fn swapping_scores() {
+        let mut state = state(10., &[(0, 1.)], &[(0, 2)], 0);
+        UpdateState::ScoresSwapped { id: 0 }.apply(&mut state);
+        assert_eq!((state.scores[&0], state.hoop_score), (0, 2));
+        UpdateState::Scored { id: 0, score: 1 }.apply(&mut state);
+        UpdateState::ScoresSwapped { id: 0 }.apply(&mut state);
+        assert_eq!((state.scores[&0], state.hoop_score), (2, 1));
+    }
+}
@@ -1,132 +1,292 @@
-use std::{pin::Pin, task::Poll};
-
-use anyhow::Context;
-use futures::Stream;
-use pin_project::pin_project;
-use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
-
-use crate::{LenType, MAX_MESSAGE_SIZE};
-
-#[pin_project]
-pub struct MessageStream<R, T> {
-    #[pin]
-    read: R,
-    read_buf: Option<Vec<u8>>,
-    _phantom: std::marker::PhantomData<T>,
-}
-
-impl<R: AsyncRead, T: DeserializeOwned> MessageStream<R, T> {
-    pub fn new(read: R) -> Self {
-        Self {
-            read,
-            read_buf: None,
-            _phantom: std::marker::PhantomData,
-        }
-    }
-}
-
-impl<R: AsyncRead, T: DeserializeOwned> Stream for MessageStream<R, T> {
-    type Item = anyhow::Result<T>;
-
-    fn poll_next(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        {
-            let this = self.as_mut().project();
-            if this.read_buf.is_none() {
-                let mut len_buf = [0u8; std::mem::size_of::<LenType>()];
-                match this.read.poll_read(cx, &mut ReadBuf::new(&mut len_buf)) {
-                    Poll::Ready(Ok(())) => {
-                        let len = u16::from_le_bytes(len_buf) as usize;
-                        if len > MAX_MESSAGE_SIZE {
-                            return Poll::Ready(Some(Err(anyhow::anyhow!(
-                                "Message too long: {}",
-                                len
-                            ))));
-                        }
-                        *this.read_buf = Some(vec![0; len]);
-                    }
-                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
-                    Poll::Pending => return Poll::Pending,
-                }
-            }
-        }
-        {
-            let this = self.as_mut().project();
-            let read_buf = this.read_buf.as_mut().unwrap();
-            match this
-                .read
-                .poll_read(cx, &mut ReadBuf::new(&mut read_buf[..]))
-            {
-                Poll::Ready(Ok(())) => {
-                    let message = match ciborium::from_reader::<T, _>(&read_buf[..]) {
-                        Ok(message) => message,
-                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
-                    };
-                    *this.read_buf = None;
-                    Poll::Ready(Some(Ok(message)))
-                }
-                Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e.into()))),
-                Poll::Pending => Poll::Pending,
-            }
-        }
-    }
-}
-
-pub async fn write_message(
-    stream: &mut (impl AsyncWrite + Unpin),
-    messsage: &impl Serialize,
-) -> anyhow::Result<()> {
-    let mut buf = vec![];
-    ciborium::ser::into_writer(messsage, &mut buf).context("Failed to serialize message")?;
-    let len = LenType::try_from(buf.len()).context("Message too long")?;
-    stream
-        .write_all(&len.to_le_bytes())
-        .await
-        .context("Failed to write message length")?;
-    stream
-        .write_all(&buf)
-        .await
-        .context("Failed to write message")?;
-    stream.flush().await.context("Failed to flush stream")?;
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use futures::StreamExt;
-    use tokio::net::{TcpListener, TcpStream};
-
-    use super::*;
-
-    #[tokio::test]
-    async fn roundtrip() {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let port = listener.local_addr().unwrap().port();
-        let messages = vec!["hello".to_owned(), "x".repeat(MAX_MESSAGE_SIZE - 3)];
-        let server_copy = messages.clone();
-        let server = tokio::spawn(async move {
-            let (stream, _) = listener.accept().await.unwrap();
-            let (_read, mut write) = stream.into_split();
-            for message in &server_copy {
-                write_message(&mut write, message).await.expect("write");
-            }
-        });
-        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
-            .await
-            .unwrap();
-        let (read, _write) = stream.into_split();
-        let mut stream = MessageStream::<_, String>::new(read);
-        let mut read_messages = vec![];
-        while read_messages.len() < messages.len() {
-            let Some(result) = stream.next().await else {
-                panic!("Stream ended before all messages were read");
-            };
-            read_messages.push(result.expect("read"));
-        }
-        assert_eq!(messages, read_messages);
-        server.await.unwrap();
-    }
-}
+use std::{marker::PhantomData, pin::Pin, task::Poll};
+
+use anyhow::{anyhow, Context};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::Stream;
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+use crate::{message_len, LenType, MAX_MESSAGE_SIZE};
+
+/// Reads the messages that `write_message` and `write_messages` send.
+#[pin_project]
+pub struct MessageStream<R, T> {
+    #[pin]
+    read: FramedRead<R, MessageCodec<(), T>>,
+}
+
+impl<R: AsyncRead, T: DeserializeOwned> MessageStream<R, T> {
+    pub fn new(read: R) -> Self {
+        Self {
+            read: FramedRead::new(read, MessageCodec::new()),
+        }
+    }
+}
+
+impl<R: AsyncRead, T: DeserializeOwned> Stream for MessageStream<R, T> {
+    type Item = anyhow::Result<T>;
+
+    /// Ends when the connection closes between messages, but fails if it closes within one.
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.project().read.poll_next(cx)
+    }
+}
+
+pub async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    messsage: &impl Serialize,
+) -> anyhow::Result<()> {
+    write_messages(stream, [messsage]).await
+}
+
+/// Writes several messages with a single flush at the end.
+pub async fn write_messages<M: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    messages: impl IntoIterator<Item = M>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    for message in messages {
+        // The length goes first, so leave room for it until the message is written.
+        let len_at = buf.len();
+        let message_at = len_at + std::mem::size_of::<LenType>();
+        buf.resize(message_at, 0);
+        ciborium::ser::into_writer(&message, &mut buf).context("Failed to serialize message")?;
+        let len = message_len(buf.len() - message_at)?;
+        buf[len_at..message_at].copy_from_slice(&len.to_le_bytes());
+    }
+    stream
+        .write_all(&buf)
+        .await
+        .context("Failed to write messages")?;
+    stream.flush().await.context("Failed to flush stream")?;
+    Ok(())
+}
+
+const LEN_SIZE: usize = std::mem::size_of::<LenType>();
+
+/// The same framing as `MessageStream` and `write_messages`, for `Framed`, `FramedRead` and
+/// `FramedWrite`: sends `S`s and receives `R`s.
+pub struct MessageCodec<S, R> {
+    _phantom: PhantomData<fn(S) -> R>,
+}
+
+impl<S, R> MessageCodec<S, R> {
+    pub fn new() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<S, R> Default for MessageCodec<S, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Serialize, R> Encoder<S> for MessageCodec<S, R> {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, message: S, dst: &mut BytesMut) -> anyhow::Result<()> {
+        // The length goes first, so leave room for it until the message is written.
+        let len_at = dst.len();
+        let message_at = len_at + LEN_SIZE;
+        dst.resize(message_at, 0);
+        let written = ciborium::ser::into_writer(&message, dst.writer())
+            .context("Failed to serialize message")
+            .and_then(|()| message_len(dst.len() - message_at));
+        match written {
+            Ok(len) => {
+                dst[len_at..message_at].copy_from_slice(&len.to_le_bytes());
+                Ok(())
+            }
+            Err(e) => {
+                // Leave the messages before it to be sent.
+                dst.truncate(len_at);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S, R: DeserializeOwned> Decoder for MessageCodec<S, R> {
+    type Item = R;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<R>> {
+        let Some(len_buf) = src.get(..LEN_SIZE) else {
+            return Ok(None);
+        };
+        let len = LenType::from_le_bytes(len_buf.try_into().unwrap()) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("Message too long: {}", len));
+        }
+        if src.len() < LEN_SIZE + len {
+            src.reserve(LEN_SIZE + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LEN_SIZE);
+        let buf = src.split_to(len);
+        ciborium::from_reader(&buf[..])
+            .context("Failed to deserialize message")
+            .map(Some)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<R>> {
+        match self.decode(src)? {
+            Some(message) => Ok(Some(message)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let partial = src.len();
+                // Don't report the same partial message again if polled after the error.
+                src.clear();
+                Err(anyhow!("Connection closed {partial} bytes into a message"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::{Framed, FramedRead};
+
+    use super::*;
+    use crate::test_util::ByteAtATime;
+
+    fn frames(messages: &[String]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        let mut codec = MessageCodec::<_, ()>::new();
+        for message in messages {
+            codec.encode(message, &mut buf).unwrap();
+        }
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn reads_byte_at_a_time() {
+        let messages = vec!["hello".to_owned(), String::new(), "x".repeat(300)];
+        let read = ByteAtATime::new(&frames(&messages));
+        let read_messages: Vec<String> = MessageStream::new(read)
+            .map(|result| result.expect("read"))
+            .collect()
+            .await;
+        assert_eq!(messages, read_messages);
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        let mut buf = BytesMut::new();
+        let mut codec = MessageCodec::<_, ()>::new();
+        codec.encode("hello", &mut buf).unwrap();
+        let too_long = "x".repeat(MAX_MESSAGE_SIZE);
+        assert!(codec.encode(too_long.as_str(), &mut buf).is_err());
+        // The message before it is still there to send.
+        assert_eq!(buf.to_vec(), frames(&["hello".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn ends_on_clean_eof() {
+        let mut stream = MessageStream::<_, String>::new(ByteAtATime::new(&[]));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_on_eof_mid_frame() {
+        let messages = vec!["hello".to_owned(), "world".to_owned()];
+        let bytes = frames(&messages);
+        // Cut off in the second message's length, then in its body.
+        for cut in [bytes.len() / 2 + 1, bytes.len() - 1] {
+            let mut stream = MessageStream::<_, String>::new(ByteAtATime::new(&bytes[..cut]));
+            assert_eq!(stream.next().await.unwrap().expect("read"), messages[0]);
+            assert!(stream.next().await.unwrap().is_err());
+            assert!(stream.next().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let messages = vec!["hello".to_owned(), "x".repeat(MAX_MESSAGE_SIZE - 3)];
+        let server_copy = messages.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut write, _read) = Framed::new(stream, MessageCodec::<_, String>::new()).split();
+            for message in server_copy {
+                write.send(message).await.expect("write");
+            }
+        });
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let (_write, mut read) = Framed::new(stream, MessageCodec::<String, String>::new()).split();
+        let mut read_messages = vec![];
+        while read_messages.len() < messages.len() {
+            let Some(result) = read.next().await else {
+                panic!("Stream ended before all messages were read");
+            };
+            read_messages.push(result.expect("read"));
+        }
+        assert_eq!(messages, read_messages);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn batched_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let messages: Vec<_> = (0..10).map(|i| "x".repeat(i * 10)).collect();
+        let server_copy = messages.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut write = Framed::new(stream, MessageCodec::<_, String>::new());
+            write
+                .send_all(&mut stream::iter(server_copy).map(Ok))
+                .await
+                .expect("write");
+        });
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let (read, _write) = stream.into_split();
+        let read_messages: Vec<_> = FramedRead::new(read, MessageCodec::<(), String>::new())
+            .take(messages.len())
+            .map(|result| result.expect("read"))
+            .collect()
+            .await;
+        assert_eq!(messages, read_messages);
+        server.await.unwrap();
+    }
+
+    /// Frames from the codec and from `write_messages` are read the same by either.
+    #[tokio::test]
+    async fn matches_write_messages() {
+        let messages: Vec<_> = (0..3).map(|i| "x".repeat(i * 100)).collect();
+        let (client, server) = tokio::io::duplex(MAX_MESSAGE_SIZE);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        write_messages(&mut server_write, &messages)
+            .await
+            .expect("write");
+        let mut client = Framed::new(client, MessageCodec::<String, String>::new());
+        for message in &messages {
+            assert_eq!(&client.next().await.unwrap().expect("read"), message);
+        }
+        client
+            .send_all(&mut stream::iter(messages.clone()).map(Ok))
+            .await
+            .expect("write");
+        let read_messages: Vec<String> = MessageStream::new(server_read)
+            .take(messages.len())
+            .map(|result| result.expect("read"))
+            .collect()
+            .await;
+        assert_eq!(messages, read_messages);
+    }
+}
@@ -0,0 +1,74 @@
+//! Helpers shared by the tests of the sync and async message streams.
+
+use std::io::{ErrorKind, Read, Write};
+
+/// Hands out its bytes one at a time, with a read that would block before each, as the slowest of
+/// connections would. Whatever is written to it is added to what's left to read.
+pub(crate) struct ByteAtATime {
+    pub(crate) bytes: Vec<u8>,
+    at: usize,
+    ready: bool,
+}
+
+impl ByteAtATime {
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            at: 0,
+            ready: false,
+        }
+    }
+
+    /// The next byte, or `None` at the end, unless this read has to wait.
+    fn next(&mut self) -> Option<Option<u8>> {
+        if !std::mem::replace(&mut self.ready, false) {
+            self.ready = true;
+            return None;
+        }
+        let byte = self.bytes.get(self.at).copied();
+        self.at += byte.is_some() as usize;
+        Some(byte)
+    }
+}
+
+impl Read for ByteAtATime {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.next() {
+            None => Err(ErrorKind::WouldBlock.into()),
+            Some(None) => Ok(0),
+            Some(Some(byte)) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+        }
+    }
+}
+
+impl Write for ByteAtATime {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncRead for ByteAtATime {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let Some(byte) = self.next() else {
+            cx.waker().wake_by_ref();
+            return std::task::Poll::Pending;
+        };
+        if let Some(byte) = byte {
+            buf.put_slice(&[byte]);
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+}
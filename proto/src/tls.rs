@@ -0,0 +1,185 @@
+//! TLS settings for either end of a connection. The messages go over TLS the same as over plain
+//! TCP, so this is only about who to trust.
+
+use std::{fmt::Display, fs::File, io::BufReader, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+
+/// The SHA-256 hash of a server's certificate, for clients to trust that certificate and no other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertificatePin([u8; 32]);
+
+impl CertificatePin {
+    pub fn of(certificate: &CertificateDer) -> Self {
+        Self(Sha256::digest(certificate).into())
+    }
+}
+
+impl Display for CertificatePin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CertificatePin {
+    type Err = String;
+
+    /// Takes hex, with or without colons between the bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: Vec<u8> = s.bytes().filter(|&c| c != b':').collect();
+        let mut pin = [0; 32];
+        if hex.len() != pin.len() * 2 {
+            return Err(format!("expected {} hex digits", pin.len() * 2));
+        }
+        for (byte, digits) in pin.iter_mut().zip(hex.chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| "not hex".to_owned())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| format!("{digits:?} isn't hex"))?;
+        }
+        Ok(Self(pin))
+    }
+}
+
+/// Which certificates a client takes a server's word for.
+#[derive(Debug, Clone)]
+pub enum ServerTrust {
+    /// Certificates from the usual public certificate authorities.
+    PublicRoots,
+    /// Certificates signed by these, as for a self-hosted server with its own authority.
+    CustomCa(Vec<CertificateDer<'static>>),
+    /// Only the certificate with this hash, whatever name or dates are on it.
+    Pinned(CertificatePin),
+}
+
+pub fn client_config(trust: ServerTrust) -> anyhow::Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?;
+    let mut roots = RootCertStore::empty();
+    let builder = match trust {
+        ServerTrust::PublicRoots => {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            builder.with_root_certificates(roots)
+        }
+        ServerTrust::CustomCa(certificates) => {
+            for certificate in certificates {
+                roots
+                    .add(certificate)
+                    .context("Failed to trust CA certificate")?;
+            }
+            builder.with_root_certificates(roots)
+        }
+        ServerTrust::Pinned(pin) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                pin,
+                algorithms: provider.signature_verification_algorithms,
+            })),
+    };
+    Ok(Arc::new(builder.with_no_client_auth()))
+}
+
+pub fn server_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> anyhow::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Failed to set up TLS")?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .context("Certificate doesn't go with the key")?;
+    Ok(Arc::new(config))
+}
+
+/// Reads the certificates in a PEM file, the server's own first if it's a chain.
+pub fn load_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path.display()))?
+        .with_context(|| format!("No private key in {}", path.display()))
+}
+
+/// Accepts the one certificate with the pinned hash. Its signature on the handshake is still
+/// checked, so it takes the certificate's key to pass.
+#[derive(Debug)]
+struct PinnedCertificate {
+    pin: CertificatePin,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if CertificatePin::of(end_entity) != self.pin {
+            return Err(rustls::Error::General(
+                "certificate doesn't match the pin".to_owned(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pin() {
+        let pin = CertificatePin([0xab; 32]);
+        assert_eq!(pin.to_string().parse(), Ok(pin));
+        let with_colons = vec!["AB"; 32].join(":");
+        assert_eq!(with_colons.parse(), Ok(pin));
+        assert!("abcd".parse::<CertificatePin>().is_err());
+        assert!("zz".repeat(32).parse::<CertificatePin>().is_err());
+    }
+}
@@ -0,0 +1,539 @@
+//! Messages over UDP. Each datagram is a packet from one side of a connection, which carries the
+//! messages that have to arrive until the other side acknowledges them, and the ones that only
+//! matter until newer ones come along.
+
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+
+use crate::message::{ToClientMessage, ToServerMessage};
+
+/// Small enough to get through loopback and most LANs, even if not in one piece.
+pub const MAX_DATAGRAM_SIZE: usize = 8192;
+/// How long without hearing from the other side before the connection counts as lost.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for an acknowledgement before sending reliable messages again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+/// How often to send something, if only acknowledgements, so the other side knows we're here.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many reliable messages can be waiting for acknowledgement before the other side counts as
+/// not keeping up.
+const MAX_UNACKED: usize = 1024;
+const CONNECTION_ID_LEN: usize = std::mem::size_of::<u64>();
+/// Follows the connection id in a retry rather than a packet. A packet can't start with it, as it
+/// isn't the start of anything in CBOR.
+const RETRY_MARKER: u8 = 0xff;
+
+/// What a listener hands a new peer to send back, showing that the peer really is at the address
+/// it sends from before the listener keeps anything for it or sends it more than one datagram.
+pub type Cookie = [u8; 32];
+
+/// Which messages have to arrive, and which can be lost because a newer one makes up for them.
+pub trait Reliability {
+    fn reliable(&self) -> bool;
+}
+
+impl Reliability for ToClientMessage {
+    fn reliable(&self) -> bool {
+        // A lost tick's moves are made up for by the next tick, and a lost snapshot by the one
+        // after. Events have to arrive.
+        !matches!(
+            self,
+            ToClientMessage::Tick { .. } | ToClientMessage::Snapshot { .. }
+        )
+    }
+}
+
+impl Reliability for ToServerMessage {
+    fn reliable(&self) -> bool {
+        // Hoop moves are relative, so each one has to arrive, but any later acknowledgement does
+        // as well as a lost one.
+        !matches!(self, ToServerMessage::AckSnapshot { .. })
+    }
+}
+
+/// Which message can start a connection, so that a listener only takes on the datagrams of
+/// clients that mean to talk to it.
+pub trait Opening {
+    fn opens_connection(&self) -> bool;
+}
+
+impl Opening for ToServerMessage {
+    fn opens_connection(&self) -> bool {
+        matches!(self, ToServerMessage::Hello { .. })
+    }
+}
+
+/// Picks an id for a new connection that nobody else can guess or is likely to have picked.
+pub fn new_connection_id() -> u64 {
+    rand::random()
+}
+
+/// Which connection a datagram belongs to, so a socket shared by many can hand it on.
+pub fn connection_id(datagram: &[u8]) -> Option<u64> {
+    let id = datagram.get(..CONNECTION_ID_LEN)?;
+    Some(u64::from_le_bytes(id.try_into().unwrap()))
+}
+
+/// A datagram asking the other side of connection `id` to send again with `cookie`.
+pub fn retry_datagram(id: u64, cookie: &Cookie) -> Vec<u8> {
+    let mut datagram = id.to_le_bytes().to_vec();
+    datagram.push(RETRY_MARKER);
+    datagram.extend_from_slice(cookie);
+    datagram
+}
+
+fn retry_cookie(datagram: &[u8]) -> Option<Cookie> {
+    match datagram.get(CONNECTION_ID_LEN..)?.split_first()? {
+        (&RETRY_MARKER, cookie) => cookie.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// The cookie a datagram sends back, if it's a packet with one.
+pub fn echoed_cookie(datagram: &[u8]) -> Option<Cookie> {
+    let packet: Packet<IgnoredAny> =
+        ciborium::from_reader(datagram.get(CONNECTION_ID_LEN..)?).ok()?;
+    packet.cookie
+}
+
+/// The address to bind a socket to for talking to `peer`, which has to be of the same family.
+pub fn local_addr_for(peer: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+/// What follows the connection id in each datagram.
+#[derive(Serialize, Deserialize, Debug)]
+struct Packet<T> {
+    /// Counts up with every packet sent, so that stale unreliable messages can be told apart.
+    sequence: u64,
+    /// How many of the other side's reliable messages arrived, in order.
+    reliable_ack: u64,
+    /// The number of the first message in `reliable`, counting from 0 for the connection.
+    first_reliable: u64,
+    /// Every reliable message not acknowledged yet, or as many as fit.
+    reliable: Vec<T>,
+    unreliable: Vec<T>,
+    /// Sent once by the side that hangs up.
+    close: bool,
+    /// The listener's cookie, sent back until the listener acknowledges something.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cookie: Option<Cookie>,
+}
+
+/// One side of a connection, keeping track of what was sent and received without doing either.
+/// Datagrams that arrive go to [`receive`](Self::receive), and [`poll_transmit`](Self::poll_transmit)
+/// says what to send back.
+pub struct UdpConnection<S, R> {
+    id: u64,
+    next_sequence: u64,
+    /// Reliable messages waiting to be acknowledged, numbered from `first_unacked`.
+    unacked: VecDeque<S>,
+    first_unacked: u64,
+    /// Unreliable messages for the next packet.
+    unreliable: Vec<S>,
+    /// Whether a reliable message was queued since the last packet, so it shouldn't wait for a
+    /// resend.
+    reliable_queued: bool,
+    last_resent: Option<Instant>,
+    last_sent: Option<Instant>,
+    /// How many of the other side's reliable messages were received.
+    delivered: u64,
+    /// The newest packet received, which unreliable messages in older ones can't beat.
+    latest_received: Option<u64>,
+    last_received: Instant,
+    ack_owed: bool,
+    closed: bool,
+    /// The cookie to send the listener back, once it asked for one.
+    cookie: Option<Cookie>,
+    _phantom: PhantomData<R>,
+}
+
+impl<S: Serialize + Reliability, R: DeserializeOwned> UdpConnection<S, R> {
+    pub fn new(id: u64, now: Instant) -> Self {
+        Self {
+            id,
+            next_sequence: 0,
+            unacked: VecDeque::new(),
+            first_unacked: 0,
+            unreliable: vec![],
+            reliable_queued: false,
+            last_resent: None,
+            last_sent: None,
+            delivered: 0,
+            latest_received: None,
+            last_received: now,
+            ack_owed: false,
+            closed: false,
+            cookie: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the other side hung up.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) > CONNECTION_TIMEOUT
+    }
+
+    pub fn queue(&mut self, message: S) -> anyhow::Result<()> {
+        if message.reliable() {
+            if self.unacked.len() >= MAX_UNACKED {
+                bail!("Too many messages waiting for acknowledgement");
+            }
+            self.unacked.push_back(message);
+            self.reliable_queued = true;
+        } else {
+            self.unreliable.push(message);
+        }
+        Ok(())
+    }
+
+    /// Takes in a datagram, returning the messages in it that weren't seen before or beaten by
+    /// newer ones. Reliable messages come out in the order they were sent.
+    pub fn receive(&mut self, datagram: &[u8], now: Instant) -> anyhow::Result<Vec<R>> {
+        if connection_id(datagram) != Some(self.id) {
+            bail!("Datagram for another connection");
+        }
+        if let Some(cookie) = retry_cookie(datagram) {
+            // Whatever the listener was sent is resent with the cookie straight away.
+            self.cookie = Some(cookie);
+            self.reliable_queued = true;
+            return Ok(vec![]);
+        }
+        let packet: Packet<R> = ciborium::from_reader(&datagram[CONNECTION_ID_LEN..])
+            .context("Failed to deserialize packet")?;
+        self.last_received = now;
+        self.closed |= packet.close;
+
+        let acked = packet
+            .reliable_ack
+            .saturating_sub(self.first_unacked)
+            .min(self.unacked.len() as u64);
+        self.unacked.drain(..acked as usize);
+        self.first_unacked += acked;
+
+        let mut messages = vec![];
+        if !packet.reliable.is_empty() {
+            self.ack_owed = true;
+        }
+        for (number, message) in (packet.first_reliable..).zip(packet.reliable) {
+            if number == self.delivered {
+                messages.push(message);
+                self.delivered += 1;
+            }
+        }
+        if self
+            .latest_received
+            .is_none_or(|latest| packet.sequence > latest)
+        {
+            self.latest_received = Some(packet.sequence);
+            messages.extend(packet.unreliable);
+        }
+        Ok(messages)
+    }
+
+    /// The next datagram to send, if there's anything to say. Call until it returns `None`.
+    pub fn poll_transmit(&mut self, now: Instant) -> anyhow::Result<Option<Vec<u8>>> {
+        let elapsed = |since: Option<Instant>, interval| {
+            since.is_none_or(|since| now.duration_since(since) >= interval)
+        };
+        let resend = !self.unacked.is_empty()
+            && (self.reliable_queued || elapsed(self.last_resent, RESEND_INTERVAL));
+        if !resend
+            && self.unreliable.is_empty()
+            && !self.ack_owed
+            && !elapsed(self.last_sent, KEEPALIVE_INTERVAL)
+        {
+            return Ok(None);
+        }
+
+        let mut reliable = if resend { self.unacked.len() } else { 0 };
+        let mut unreliable = self.unreliable.len();
+        let datagram = loop {
+            let datagram = self.encode(reliable, unreliable)?;
+            if datagram.len() <= MAX_DATAGRAM_SIZE {
+                break datagram;
+            }
+            // Whatever doesn't fit goes in the next packet. Every message fits in one on its own.
+            if unreliable > 1 {
+                unreliable /= 2;
+            } else if unreliable == 1 && reliable > 0 {
+                unreliable = 0;
+            } else if reliable > 1 {
+                reliable /= 2;
+            } else {
+                bail!("Message too long for a datagram");
+            }
+        };
+        self.unreliable.drain(..unreliable);
+        if resend {
+            self.reliable_queued = false;
+            self.last_resent = Some(now);
+        }
+        self.ack_owed = false;
+        self.last_sent = Some(now);
+        self.next_sequence += 1;
+        Ok(Some(datagram))
+    }
+
+    fn encode(&self, reliable: usize, unreliable: usize) -> anyhow::Result<Vec<u8>> {
+        self.encode_packet(Packet {
+            sequence: self.next_sequence,
+            reliable_ack: self.delivered,
+            first_reliable: self.first_unacked,
+            reliable: self.unacked.iter().take(reliable).collect(),
+            unreliable: self.unreliable[..unreliable].iter().collect(),
+            close: false,
+            cookie: self.cookie.filter(|_| self.first_unacked == 0),
+        })
+    }
+}
+
+impl<S, R> UdpConnection<S, R> {
+    /// A datagram telling the other side that this one is hanging up.
+    pub fn close(&mut self) -> anyhow::Result<Vec<u8>> {
+        let datagram = self.encode_packet(Packet::<()> {
+            sequence: self.next_sequence,
+            reliable_ack: self.delivered,
+            first_reliable: self.first_unacked,
+            reliable: vec![],
+            unreliable: vec![],
+            close: true,
+            cookie: None,
+        })?;
+        self.next_sequence += 1;
+        Ok(datagram)
+    }
+
+    fn encode_packet<T: Serialize>(&self, packet: Packet<T>) -> anyhow::Result<Vec<u8>> {
+        let mut datagram = self.id.to_le_bytes().to_vec();
+        ciborium::into_writer(&packet, &mut datagram).context("Failed to serialize packet")?;
+        Ok(datagram)
+    }
+}
+
+/// A blocking-free connection to a server over UDP, for a client that checks for messages every
+/// frame like [`sync::MessageStream`](crate::sync::MessageStream).
+pub struct UdpMessageStream<S, R> {
+    socket: UdpSocket,
+    connection: UdpConnection<S, R>,
+}
+
+impl<S: Serialize + Reliability, R: DeserializeOwned> UdpMessageStream<S, R> {
+    /// Nothing is sent until the first message, and whether anyone's listening only shows when
+    /// nothing comes back for [`CONNECTION_TIMEOUT`].
+    pub fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let peer = addr
+            .to_socket_addrs()?
+            .next()
+            .context("No address to connect to")?;
+        let socket = UdpSocket::bind(local_addr_for(peer)).context("Failed to bind UDP socket")?;
+        socket
+            .connect(peer)
+            .context("Failed to connect UDP socket")?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            connection: UdpConnection::new(new_connection_id(), Instant::now()),
+        })
+    }
+
+    /// Returns the messages that arrived since the last call, and sends any that are due again.
+    pub fn read_messages(&mut self) -> anyhow::Result<Vec<R>> {
+        let now = Instant::now();
+        let mut messages = vec![];
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => match self.connection.receive(&buf[..len], now) {
+                    Ok(received) => messages.extend(received),
+                    // Garbage doesn't end the connection, or anyone could end it.
+                    Err(_) => continue,
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e).context("Failed to receive datagram"),
+            }
+        }
+        if self.connection.closed() {
+            bail!("Server closed the connection");
+        }
+        if self.connection.timed_out(now) {
+            bail!("Server stopped answering");
+        }
+        self.transmit(now)?;
+        Ok(messages)
+    }
+
+    pub fn write_message(&mut self, message: S) -> anyhow::Result<()> {
+        self.connection.queue(message)?;
+        self.transmit(Instant::now())
+    }
+
+    fn transmit(&mut self, now: Instant) -> anyhow::Result<()> {
+        while let Some(datagram) = self.connection.poll_transmit(now)? {
+            match self.socket.send(&datagram) {
+                Ok(_) => {}
+                // It's as good as lost, which the connection copes with.
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to send datagram"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S, R> Drop for UdpMessageStream<S, R> {
+    fn drop(&mut self) {
+        if let Ok(datagram) = self.connection.close() {
+            _ = self.socket.send(&datagram);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::UpdateState;
+
+    use super::*;
+
+    /// Drops about a third of datagrams, the same ones every run.
+    struct Lossy(u64);
+
+    impl Lossy {
+        fn lose(&mut self) -> bool {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (self.0 >> 33).is_multiple_of(3)
+        }
+    }
+
+    fn tick(tick: u64) -> ToClientMessage {
+        ToClientMessage::Tick {
+            tick,
+            updates: vec![UpdateState::MoveHoop {
+                x: tick as f32,
+                sequence: tick as u32,
+            }],
+        }
+    }
+
+    fn scored(tick: u64) -> ToClientMessage {
+        ToClientMessage::Events {
+            tick,
+            updates: vec![UpdateState::Scored {
+                id: 0,
+                score: tick as u32,
+            }],
+        }
+    }
+
+    #[test]
+    fn converges_despite_loss() {
+        let start = Instant::now();
+        let mut server = UdpConnection::<ToClientMessage, ToServerMessage>::new(7, start);
+        let mut client = UdpConnection::<ToServerMessage, ToClientMessage>::new(7, start);
+        let mut loss = Lossy(1);
+        let mut to_client = vec![];
+        let mut to_server = vec![];
+        let mut latest_tick = None;
+        for step in 0..200u64 {
+            let now = start + RESEND_INTERVAL / 4 * step as u32;
+            if step < 20 {
+                server.queue(scored(step)).unwrap();
+                client.queue(ToServerMessage::ListGames).unwrap();
+            }
+            server.queue(tick(step)).unwrap();
+            while let Some(datagram) = server.poll_transmit(now).unwrap() {
+                if !loss.lose() {
+                    for message in client.receive(&datagram, now).unwrap() {
+                        match message {
+                            ToClientMessage::Tick { tick, .. } => {
+                                assert!(latest_tick < Some(tick), "stale tick {tick}");
+                                latest_tick = Some(tick);
+                            }
+                            message => to_client.push(message),
+                        }
+                    }
+                }
+            }
+            while let Some(datagram) = client.poll_transmit(now).unwrap() {
+                if !loss.lose() {
+                    to_server.extend(server.receive(&datagram, now).unwrap());
+                }
+            }
+        }
+        let expected: Vec<_> = (0..20u64).map(scored).collect();
+        assert_eq!(to_client, expected);
+        assert_eq!(to_server.len(), 20);
+        assert!(server.unacked.is_empty() && client.unacked.is_empty());
+        assert!(latest_tick > Some(190));
+    }
+
+    /// `message` with its one update repeated for as long as it fits in a message.
+    fn filled(message: ToClientMessage) -> ToClientMessage {
+        let mut filled = message;
+        loop {
+            let mut bigger = filled.clone();
+            let (ToClientMessage::Tick { updates, .. } | ToClientMessage::Events { updates, .. }) =
+                &mut bigger
+            else {
+                unreachable!()
+            };
+            updates.push(updates[0].clone());
+            let mut encoded = vec![];
+            ciborium::into_writer(&bigger, &mut encoded).unwrap();
+            if encoded.len() > crate::MAX_MESSAGE_SIZE {
+                return filled;
+            }
+            filled = bigger;
+        }
+    }
+
+    #[test]
+    fn sends_long_messages_apart() {
+        let now = Instant::now();
+        let mut server = UdpConnection::<ToClientMessage, ToServerMessage>::new(3, now);
+        let mut client = UdpConnection::<ToServerMessage, ToClientMessage>::new(3, now);
+        let messages = vec![filled(scored(0)), filled(tick(0))];
+        for message in &messages {
+            server.queue(message.clone()).unwrap();
+        }
+        let mut received = vec![];
+        while let Some(datagram) = server.poll_transmit(now).unwrap() {
+            received.extend(client.receive(&datagram, now).unwrap());
+        }
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn ignores_other_connections() {
+        let now = Instant::now();
+        let mut ours = UdpConnection::<ToServerMessage, ToClientMessage>::new(1, now);
+        let mut theirs = UdpConnection::<ToClientMessage, ToServerMessage>::new(2, now);
+        theirs.queue(tick(0)).unwrap();
+        let datagram = theirs.poll_transmit(now).unwrap().unwrap();
+        assert!(ours.receive(&datagram, now).is_err());
+        assert!(ours.receive(&[1, 0, 0, 0, 0, 0, 0, 0, 0xff], now).is_err());
+    }
+}
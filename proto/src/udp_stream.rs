@@ -0,0 +1,635 @@
+//! Async UDP connections, each run by a task of its own so that resends and keepalives go out
+//! whether or not anyone is reading or writing.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use futures::Stream;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::udp::{
+    connection_id, echoed_cookie, local_addr_for, new_connection_id, retry_datagram, Cookie,
+    Opening, Reliability, UdpConnection, MAX_DATAGRAM_SIZE,
+};
+
+/// How often a connection checks whether anything is due to be sent again.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const CHANNEL_SIZE: usize = 64;
+/// How many ended connections a listener remembers, so that their late datagrams don't start new
+/// ones.
+const MAX_ENDED_CONNECTIONS: usize = 1024;
+/// How long a cookie is good for, give or take as much again.
+const COOKIE_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The messages that arrive on a connection. It ends when the other side hangs up, and gives an
+/// error if it stops answering.
+pub struct UdpReader<R> {
+    messages: mpsc::Receiver<anyhow::Result<R>>,
+}
+
+impl<R> Stream for UdpReader<R> {
+    type Item = anyhow::Result<R>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+/// Sends messages on a connection. Dropping it hangs up.
+pub struct UdpWriter<S> {
+    messages: mpsc::Sender<Vec<S>>,
+}
+
+impl<S> UdpWriter<S> {
+    /// Sends `messages` in the same datagram where they fit.
+    pub async fn write_messages(&mut self, messages: Vec<S>) -> anyhow::Result<()> {
+        self.messages
+            .send(messages)
+            .await
+            .map_err(|_| anyhow!("Connection closed"))
+    }
+}
+
+/// Accepts connections on a UDP socket, telling them apart by the id each datagram starts with.
+pub struct UdpListener<S, R> {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(UdpReader<R>, UdpWriter<S>, SocketAddr)>,
+}
+
+impl<S, R> UdpListener<S, R>
+where
+    S: Serialize + Reliability + Send + 'static,
+    R: DeserializeOwned + Opening + Send + 'static,
+{
+    pub async fn bind(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (incoming_tx, incoming) = mpsc::channel(CHANNEL_SIZE);
+        tokio::spawn(demultiplex(socket, incoming_tx));
+        Ok(Self {
+            local_addr,
+            incoming,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The next new connection, once a datagram opening it arrives.
+    pub async fn accept(&mut self) -> Option<(UdpReader<R>, UdpWriter<S>, SocketAddr)> {
+        self.incoming.recv().await
+    }
+}
+
+/// A connection the listener hands datagrams to.
+struct Route {
+    /// The only address its datagrams are taken from. Anyone who saw the connection's id could
+    /// send from elsewhere, so a peer that moves has to connect again.
+    peer: SocketAddr,
+    datagrams: mpsc::Sender<Vec<u8>>,
+}
+
+/// The connections that ended, so that their datagrams still on the way are dropped.
+#[derive(Default)]
+struct Ended {
+    ids: HashSet<u64>,
+    /// Oldest first, to forget when there are too many.
+    order: VecDeque<u64>,
+}
+
+impl Ended {
+    fn insert(&mut self, id: u64) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > MAX_ENDED_CONNECTIONS {
+            let forgotten = self.order.pop_front().unwrap();
+            self.ids.remove(&forgotten);
+        }
+    }
+}
+
+/// Makes the cookies that new peers send back, without remembering anything about the peers.
+struct Cookies {
+    key: [u8; 32],
+    started: Instant,
+}
+
+impl Cookies {
+    fn new() -> Self {
+        Self {
+            key: rand::random(),
+            started: Instant::now(),
+        }
+    }
+
+    fn epoch(&self) -> u64 {
+        self.started.elapsed().as_secs() / COOKIE_LIFETIME.as_secs()
+    }
+
+    fn mac(&self, addr: SocketAddr, id: u64, epoch: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key");
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_le_bytes());
+        mac.update(&id.to_le_bytes());
+        mac.update(&epoch.to_le_bytes());
+        mac
+    }
+
+    fn cookie(&self, addr: SocketAddr, id: u64) -> Cookie {
+        self.mac(addr, id, self.epoch())
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    /// Whether a cookie was made for this address and id, in this epoch or the one before.
+    fn check(&self, addr: SocketAddr, id: u64, cookie: &Cookie) -> bool {
+        let epoch = self.epoch();
+        [Some(epoch), epoch.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .any(|epoch| self.mac(addr, id, epoch).verify_slice(cookie).is_ok())
+    }
+}
+
+/// Hands each datagram to its connection, starting one for ids it hasn't seen if the datagram
+/// opens it and comes back with a cookie for the address it's from.
+async fn demultiplex<S, R>(
+    socket: Arc<UdpSocket>,
+    incoming: mpsc::Sender<(UdpReader<R>, UdpWriter<S>, SocketAddr)>,
+) where
+    S: Serialize + Reliability + Send + 'static,
+    R: DeserializeOwned + Opening + Send + 'static,
+{
+    let mut connections: HashMap<u64, Route> = HashMap::new();
+    let mut ended = Ended::default();
+    let cookies = Cookies::new();
+    let (ended_tx, mut ended_rx) = mpsc::unbounded_channel();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            Some(id) = ended_rx.recv() => {
+                connections.remove(&id);
+                ended.insert(id);
+                continue;
+            }
+        };
+        // Errors here, like ICMP port unreachable from a peer that went away, are about one peer
+        // and not the socket.
+        let Ok((len, addr)) = received else {
+            continue;
+        };
+        let datagram = &buf[..len];
+        let Some(id) = connection_id(datagram) else {
+            continue;
+        };
+        if let Some(route) = connections.get(&id) {
+            if route.peer == addr {
+                // A connection that can't keep up loses datagrams, as the network might.
+                _ = route.datagrams.try_send(datagram.to_vec());
+            }
+            continue;
+        }
+        if ended.ids.contains(&id) {
+            continue;
+        }
+        // A forged source address never sees the cookie, so it can't get anything started, and
+        // gets no more than it sent for each datagram.
+        if !echoed_cookie(datagram).is_some_and(|cookie| cookies.check(addr, id, &cookie)) {
+            let retry = retry_datagram(id, &cookies.cookie(addr, id));
+            if retry.len() <= datagram.len() {
+                _ = socket.send_to(&retry, addr).await;
+            }
+            continue;
+        }
+        // Nothing is started for a datagram that isn't the start of a conversation, so that it
+        // takes more than some bytes from anywhere to have the server do any work.
+        let mut connection = UdpConnection::new(id, Instant::now());
+        let opening = match connection.receive(datagram, Instant::now()) {
+            Ok(messages) if messages.first().is_some_and(R::opens_connection) => messages,
+            _ => continue,
+        };
+        let (datagrams_tx, datagrams) = mpsc::channel(CHANNEL_SIZE);
+        let (reader, writer, outgoing, delivered) = channels();
+        // Waiting for the connection to be taken would hold up every other one's datagrams.
+        match incoming.try_send((reader, writer, addr)) {
+            Ok(()) => {}
+            // The peer sends its hello again, to be taken once there's room.
+            Err(TrySendError::Full(_)) => continue,
+            // Nobody's listening any more.
+            Err(TrySendError::Closed(_)) => return,
+        }
+        let socket = socket.clone();
+        let ended_tx = ended_tx.clone();
+        tokio::spawn(async move {
+            for message in opening {
+                _ = delivered.send(Ok(message)).await;
+            }
+            run_connection(connection, socket, addr, datagrams, outgoing, delivered).await;
+            _ = ended_tx.send(id);
+        });
+        connections.insert(
+            id,
+            Route {
+                peer: addr,
+                datagrams: datagrams_tx,
+            },
+        );
+    }
+}
+
+/// Starts a connection to a [`UdpListener`]. Like the listener's, it's run by a task of its own.
+pub async fn connect<S, R>(addr: impl ToSocketAddrs) -> anyhow::Result<(UdpReader<R>, UdpWriter<S>)>
+where
+    S: Serialize + Reliability + Send + 'static,
+    R: DeserializeOwned + Send + 'static,
+{
+    let peer = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("No address to connect to"))?;
+    let socket = Arc::new(UdpSocket::bind(local_addr_for(peer)).await?);
+    socket.connect(peer).await?;
+    let (datagrams_tx, datagrams) = mpsc::channel(CHANNEL_SIZE);
+    let receive_socket = socket.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let received = tokio::select! {
+                received = receive_socket.recv(&mut buf) => received,
+                _ = datagrams_tx.closed() => return,
+            };
+            if let Ok(len) = received {
+                _ = datagrams_tx.send(buf[..len].to_vec()).await;
+            }
+        }
+    });
+    let (reader, writer, outgoing, delivered) = channels();
+    let connection = UdpConnection::new(new_connection_id(), Instant::now());
+    tokio::spawn(run_connection(
+        connection, socket, peer, datagrams, outgoing, delivered,
+    ));
+    Ok((reader, writer))
+}
+
+type Channels<S, R> = (
+    UdpReader<R>,
+    UdpWriter<S>,
+    mpsc::Receiver<Vec<S>>,
+    mpsc::Sender<anyhow::Result<R>>,
+);
+
+fn channels<S, R>() -> Channels<S, R> {
+    let (delivered, messages) = mpsc::channel(CHANNEL_SIZE);
+    let (messages_tx, outgoing) = mpsc::channel(CHANNEL_SIZE);
+    (
+        UdpReader { messages },
+        UdpWriter {
+            messages: messages_tx,
+        },
+        outgoing,
+        delivered,
+    )
+}
+
+/// Sends and receives for one connection until either side hangs up or it times out.
+async fn run_connection<S, R>(
+    mut connection: UdpConnection<S, R>,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    mut outgoing: mpsc::Receiver<Vec<S>>,
+    delivered: mpsc::Sender<anyhow::Result<R>>,
+) where
+    S: Serialize + Reliability,
+    R: DeserializeOwned,
+{
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let result = loop {
+        let event = tokio::select! {
+            datagram = datagrams.recv() => Event::Datagram(datagram),
+            messages = outgoing.recv() => Event::Outgoing(messages),
+            _ = poll.tick() => Event::Poll,
+        };
+        match connection.handle(event, &delivered).await {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+        if let Err(e) = transmit(&mut connection, &socket, peer).await {
+            break Err(e);
+        }
+    };
+    match result {
+        Ok(()) => {
+            if let Ok(datagram) = connection.close() {
+                _ = socket.send_to(&datagram, peer).await;
+            }
+        }
+        Err(e) => _ = delivered.send(Err(e)).await,
+    }
+}
+
+enum Event<S> {
+    Datagram(Option<Vec<u8>>),
+    Outgoing(Option<Vec<S>>),
+    Poll,
+}
+
+impl<S: Serialize + Reliability, R: DeserializeOwned> UdpConnection<S, R> {
+    /// Returns whether the connection is still open.
+    async fn handle(
+        &mut self,
+        event: Event<S>,
+        delivered: &mpsc::Sender<anyhow::Result<R>>,
+    ) -> anyhow::Result<bool> {
+        match event {
+            Event::Datagram(None) | Event::Outgoing(None) => return Ok(false),
+            Event::Datagram(Some(datagram)) => {
+                // Garbage doesn't end the connection, or anyone could end it.
+                let Ok(messages) = self.receive(&datagram, Instant::now()) else {
+                    return Ok(true);
+                };
+                for message in messages {
+                    // The reader may be gone while the writer isn't, and that's up to its owner.
+                    _ = delivered.send(Ok(message)).await;
+                }
+                if self.closed() {
+                    return Ok(false);
+                }
+            }
+            Event::Outgoing(Some(messages)) => {
+                for message in messages {
+                    self.queue(message)?;
+                }
+            }
+            Event::Poll => {
+                if self.timed_out(Instant::now()) {
+                    return Err(anyhow!("Connection timed out"));
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+async fn transmit<S: Serialize + Reliability, R: DeserializeOwned>(
+    connection: &mut UdpConnection<S, R>,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+) -> anyhow::Result<()> {
+    while let Some(datagram) = connection.poll_transmit(Instant::now())? {
+        // A datagram that can't be sent is as good as lost, which the connection copes with.
+        _ = socket.send_to(&datagram, peer).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::{
+        message::{ToClientMessage, ToServerMessage, PROTOCOL_VERSION},
+        state::UpdateState,
+    };
+
+    use super::*;
+
+    type Listener = UdpListener<ToClientMessage, ToServerMessage>;
+    type ClientConnection = UdpConnection<ToServerMessage, ToClientMessage>;
+
+    /// How long to give a datagram that should be ignored to not be.
+    const IGNORED_FOR: Duration = Duration::from_millis(200);
+    /// Long enough for a few resends, if anything were kept to resend.
+    const CONNECTION_GONE_FOR: Duration = Duration::from_millis(500);
+
+    fn hello() -> ToServerMessage {
+        ToServerMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+        }
+    }
+
+    /// A datagram carrying `messages`, as the next one from `connection`.
+    fn datagram(connection: &mut ClientConnection, messages: Vec<ToServerMessage>) -> Vec<u8> {
+        for message in messages {
+            connection.queue(message).unwrap();
+        }
+        connection.poll_transmit(Instant::now()).unwrap().unwrap()
+    }
+
+    /// Sends `messages` from `socket`, and returns the datagram sending them again with the cookie
+    /// the listener answered with.
+    async fn with_cookie(
+        socket: &UdpSocket,
+        connection: &mut ClientConnection,
+        messages: Vec<ToServerMessage>,
+    ) -> Vec<u8> {
+        socket.send(&datagram(connection, messages)).await.unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert!(connection
+            .receive(&buf[..len], Instant::now())
+            .unwrap()
+            .is_empty());
+        connection.poll_transmit(Instant::now()).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr();
+        let (mut client_read, mut client_write) = connect::<ToServerMessage, ToClientMessage>(addr)
+            .await
+            .unwrap();
+        client_write
+            .write_messages(vec![hello(), ToServerMessage::ListGames])
+            .await
+            .unwrap();
+        let (mut server_read, mut server_write, _) = listener.accept().await.unwrap();
+        assert_eq!(server_read.next().await.unwrap().unwrap(), hello());
+        assert_eq!(
+            server_read.next().await.unwrap().unwrap(),
+            ToServerMessage::ListGames
+        );
+        let messages = vec![
+            ToClientMessage::RoleSwapAnswered { accepted: true },
+            ToClientMessage::Tick {
+                tick: 1,
+                updates: vec![UpdateState::MoveHoop { x: 1., sequence: 1 }],
+            },
+        ];
+        server_write.write_messages(messages.clone()).await.unwrap();
+        let mut received = vec![
+            client_read.next().await.unwrap().unwrap(),
+            client_read.next().await.unwrap().unwrap(),
+        ];
+        received.sort_by_key(|message| matches!(message, ToClientMessage::Tick { .. }));
+        assert_eq!(received, messages);
+
+        drop(client_write);
+        assert!(server_read.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn connects_over_ipv6() {
+        let mut listener = Listener::bind("[::1]:0").await.unwrap();
+        let (_client_read, mut client_write) =
+            connect::<ToServerMessage, ToClientMessage>(listener.local_addr())
+                .await
+                .unwrap();
+        client_write.write_messages(vec![hello()]).await.unwrap();
+        let (mut server_read, _server_write, _) = listener.accept().await.unwrap();
+        assert_eq!(server_read.next().await.unwrap().unwrap(), hello());
+    }
+
+    #[tokio::test]
+    async fn only_a_hello_opens_a_connection() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener.local_addr()).await.unwrap();
+
+        let mut stranger = ClientConnection::new(1, Instant::now());
+        let list = with_cookie(&socket, &mut stranger, vec![ToServerMessage::ListGames]).await;
+        socket.send(&list).await.unwrap();
+        socket.send(&[1, 0, 0, 0, 0, 0, 0, 0, 0xfe]).await.unwrap();
+        let accepted = tokio::time::timeout(IGNORED_FOR, listener.accept()).await;
+        assert!(accepted.is_err(), "accepted a connection without a hello");
+
+        let mut client = ClientConnection::new(2, Instant::now());
+        let opening = with_cookie(&socket, &mut client, vec![hello()]).await;
+        socket.send(&opening).await.unwrap();
+        let (mut read, write, _) = listener.accept().await.unwrap();
+        assert_eq!(read.next().await.unwrap().unwrap(), hello());
+
+        // Once it's over, the hello arriving again doesn't start it over.
+        drop(write);
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        while !client.closed() {
+            let len = socket.recv(&mut buf).await.unwrap();
+            client.receive(&buf[..len], Instant::now()).unwrap();
+        }
+        assert!(read.next().await.is_none());
+        socket.send(&opening).await.unwrap();
+        let accepted = tokio::time::timeout(IGNORED_FOR, listener.accept()).await;
+        assert!(accepted.is_err(), "a closed connection was opened again");
+    }
+
+    #[tokio::test]
+    async fn connections_stay_with_their_peer() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener.local_addr()).await.unwrap();
+        let mut client = ClientConnection::new(3, Instant::now());
+        let opening = with_cookie(&socket, &mut client, vec![hello()]).await;
+        socket.send(&opening).await.unwrap();
+        let (mut read, _write, _) = listener.accept().await.unwrap();
+        assert_eq!(read.next().await.unwrap().unwrap(), hello());
+
+        // Someone else who knows the id can't take the connection over.
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        spoofer.connect(listener.local_addr()).await.unwrap();
+        let mut spoofed = ClientConnection::new(3, Instant::now());
+        let takeover = datagram(&mut spoofed, vec![hello(), ToServerMessage::ListGames]);
+        spoofer.send(&takeover).await.unwrap();
+        let delivered = tokio::time::timeout(IGNORED_FOR, read.next()).await;
+        assert!(delivered.is_err(), "got {delivered:?} from another peer");
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let answered = tokio::time::timeout(IGNORED_FOR, spoofer.recv(&mut buf)).await;
+        assert!(answered.is_err(), "answered another peer");
+    }
+
+    #[tokio::test]
+    async fn forged_hellos_get_one_small_answer() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener.local_addr()).await.unwrap();
+        let mut client = ClientConnection::new(4, Instant::now());
+        let forged = datagram(&mut client, vec![hello()]);
+        socket.send(&forged).await.unwrap();
+
+        // Just the cookie comes back, never bigger than what was sent, and nothing is kept to
+        // send again or accept.
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert!(len <= forged.len());
+        let again = tokio::time::timeout(CONNECTION_GONE_FOR, socket.recv(&mut buf)).await;
+        assert!(again.is_err(), "answered again");
+        let accepted = tokio::time::timeout(IGNORED_FOR, listener.accept()).await;
+        assert!(accepted.is_err(), "accepted a connection without a cookie");
+    }
+
+    #[tokio::test]
+    async fn cookies_are_for_one_address() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener.local_addr()).await.unwrap();
+        let mut client = ClientConnection::new(5, Instant::now());
+        let opening = with_cookie(&socket, &mut client, vec![hello()]).await;
+
+        let elsewhere = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        elsewhere.connect(listener.local_addr()).await.unwrap();
+        elsewhere.send(&opening).await.unwrap();
+        let accepted = tokio::time::timeout(IGNORED_FOR, listener.accept()).await;
+        assert!(accepted.is_err(), "accepted a cookie from another address");
+
+        socket.send(&opening).await.unwrap();
+        let (mut read, _write, _) = listener.accept().await.unwrap();
+        assert_eq!(read.next().await.unwrap().unwrap(), hello());
+    }
+
+    #[tokio::test]
+    async fn connections_waiting_to_be_taken_hold_up_no_others() {
+        let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let (_client_read, mut client_write) =
+            connect::<ToServerMessage, ToClientMessage>(listener.local_addr())
+                .await
+                .unwrap();
+        client_write.write_messages(vec![hello()]).await.unwrap();
+        let (mut read, _write, _) = listener.accept().await.unwrap();
+        assert_eq!(read.next().await.unwrap().unwrap(), hello());
+
+        // More new connections than there's room for, none of them taken.
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener.local_addr()).await.unwrap();
+        let mut openings = vec![];
+        for id in 0..CHANNEL_SIZE as u64 + 2 {
+            let mut waiting = ClientConnection::new(100 + id, Instant::now());
+            openings.push(with_cookie(&socket, &mut waiting, vec![hello()]).await);
+        }
+        for opening in &openings {
+            socket.send(opening).await.unwrap();
+        }
+
+        client_write
+            .write_messages(vec![ToServerMessage::ListGames])
+            .await
+            .unwrap();
+        let delivered = tokio::time::timeout(CONNECTION_GONE_FOR, read.next()).await;
+        assert_eq!(
+            delivered.unwrap().unwrap().unwrap(),
+            ToServerMessage::ListGames
+        );
+    }
+}
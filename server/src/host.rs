@@ -1,154 +1,925 @@
-use std::time::Duration;
-
-use anyhow::{anyhow, Context};
-use futures::{future::select_all, StreamExt};
-use nope_the_hoop_proto::{
-    message::{ToClientMessage, ToServerMessage},
-    state::UpdateState,
-    stream::{write_message, MessageStream},
-};
-use tokio::{
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::mpsc,
-    time::{Instant, MissedTickBehavior},
-};
-use tracing::{error, info, trace};
-
-use crate::sim::Game;
-
-pub(crate) type ServerMessageStream = MessageStream<OwnedReadHalf, ToServerMessage>;
-
-const FRAME_DURATION: Duration = Duration::from_millis(16);
-
-pub struct GameHost {
-    connection_tx: mpsc::Sender<(ServerMessageStream, OwnedWriteHalf)>,
-    end_rx: mpsc::Receiver<u32>,
-}
-
-impl GameHost {
-    pub fn new(id: u32) -> Self {
-        info!("Starting game {}", id);
-        let (connection_tx, connection_rx) = mpsc::channel(4);
-        let (end_tx, end_rx) = mpsc::channel(1);
-        tokio::spawn(async move {
-            let result = game_loop(connection_rx, id).await;
-            end_tx.send(id).await.expect("Sending end to a live server");
-            if let Err(e) = result {
-                error!("Game loop error for game {id}: {:#}", e);
-            }
-        });
-        Self {
-            connection_tx,
-            end_rx,
-        }
-    }
-
-    pub async fn await_end(&mut self) -> u32 {
-        self.end_rx.recv().await.expect("Awaiting end of game")
-    }
-
-    pub async fn new_client(&self, read_wrap: ServerMessageStream, write: OwnedWriteHalf) {
-        self.connection_tx
-            .send((read_wrap, write))
-            .await
-            .expect("Sending new client");
-    }
-}
-
-struct Client {
-    read: ServerMessageStream,
-    write: OwnedWriteHalf,
-}
-
-async fn read_one_client_message(
-    clients: &mut [Client],
-) -> (usize, anyhow::Result<ToServerMessage>) {
-    if clients.is_empty() {
-        let () = futures::future::pending().await;
-        unreachable!()
-    }
-    let (result, client_index, _) = select_all(
-        clients
-            .iter_mut()
-            .map(|client| Box::pin(client.read.next())),
-    )
-    .await;
-    let result = result.unwrap_or(Err(anyhow!("Client closed connection")));
-    (client_index, result)
-}
-
-async fn game_loop(
-    mut connection_rx: mpsc::Receiver<(ServerMessageStream, OwnedWriteHalf)>,
-    id: u32,
-) -> anyhow::Result<()> {
-    let mut game = Game::default();
-    let mut clients: Vec<Client> = vec![];
-    let mut frame_timer = tokio::time::interval(FRAME_DURATION);
-    frame_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
-    let mut last_frame_time = Instant::now();
-    loop {
-        let mut updates = vec![];
-        tokio::select! {
-            new_connection = connection_rx.recv() => {
-                let (read, mut write) = new_connection.context("Failed to receive connection")?;
-                write_message(&mut write, &ToClientMessage::InitialState(game.state().clone())).await?;
-                let mut client = Client { read, write };
-                if clients.is_empty() {
-                    info!("Game {} has its first client", id);
-                    write_message(&mut client.write, &ToClientMessage::EstablishAsHoop).await?;
-                } else if clients.len() == 1 {
-                    info!("Game {} has its first ball", id);
-                    write_message(&mut client.write, &ToClientMessage::EstablishAsBall {
-                        id: 0,
-                    }).await?;
-                }
-                clients.push(client);
-            }
-            (client_index, result) = read_one_client_message(&mut clients) => {
-                let message = match result {
-                    Ok(message) => message,
-                    Err(e) => {
-                        info!("Client {} in game {id} read error (terminating): {:#}", client_index, e);
-                        _ = clients.remove(client_index);
-                        continue;
-                    }
-                };
-                match message {
-                    ToServerMessage::MoveHoop {
-                        direction,
-                        seconds_pressed,
-                    } => {
-                        trace!("Client {} in game {id} moved hoop: {:?}", client_index, direction);
-                        game.move_hoop(direction, seconds_pressed);
-                        updates.push(ToClientMessage::UpdateState(UpdateState::MoveHoop { x: game.state().hoop_x }));
-                    }
-                    ToServerMessage::ShootBall {
-                        id,
-                        angle,
-                        seconds_pressed,
-                    } => {
-                        trace!("Client {} in game {id} shot ball: {:?}", client_index, id);
-                        game.shoot_ball(id, angle, seconds_pressed);
-                    }
-                    ToServerMessage::Hello { .. } => {
-                        error!("Client {} in game {id} sent Hello after initial hello - terminating", client_index);
-                        _ = clients.remove(client_index);
-                    }
-                }
-            }
-            _ = frame_timer.tick() => {
-                let now = Instant::now();
-                let elapsed = now - last_frame_time;
-                last_frame_time = now;
-                game.update(elapsed, &mut updates);
-            }
-        }
-        for update in updates {
-            // TODO: Update concurrently, and don't let a slow client slow everyone down
-            for client in &mut clients {
-                trace!("Sending update to client: {update:?}");
-                write_message(&mut client.write, &update).await?;
-            }
-        }
-    }
-}
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::{anyhow, Context};
+use futures::{future::select_all, StreamExt};
+use nope_the_hoop_proto::{
+    auth::{GamePassword, SessionToken},
+    lobby::GameInfo,
+    message::{CommandError, PreferredRole, ToClientMessage, ToServerMessage},
+    state::{GameState, MatchPhase, StateDelta, UpdateState},
+};
+use nope_the_hoop_sim::{Game, InputViolation, TICK_DURATION};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    outbox::Outbox,
+    rules::{standings, Match, MatchInputs, MatchRules},
+    transport::{MessageReader, MessageWriter},
+};
+
+/// A client connection that finished its handshake.
+pub(crate) struct Connection {
+    pub(crate) read: MessageReader,
+    pub(crate) write: Box<dyn MessageWriter>,
+    /// The session the client wants to resume, if any.
+    pub(crate) session: Option<SessionToken>,
+    pub(crate) preferred_role: Option<PreferredRole>,
+}
+
+/// The most ticks run in one go to catch up after the loop falls behind. Any more time than that
+/// is dropped, slowing the game down rather than stalling it.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+/// How often, in ticks, clients are sent a snapshot of the whole game state.
+const SNAPSHOT_INTERVAL: u64 = 15;
+/// How many past snapshots are kept for clients' acknowledgements to refer to. A client that
+/// hasn't acknowledged any of them is sent the whole state.
+const SNAPSHOT_HISTORY: usize = 8;
+
+/// Settings shared by all the games on a server.
+#[derive(Debug, Clone)]
+pub struct GameConfig {
+    /// Clients that join when this many are already shooting balls become observers.
+    pub max_ball_players: usize,
+    /// How long a game lives on without any clients.
+    pub idle_timeout: Duration,
+    /// How long a game lives at all, if limited.
+    pub max_duration: Option<Duration>,
+    /// How long a disconnected client's role is kept for it to reconnect to.
+    pub reconnect_grace: Duration,
+    pub rules: MatchRules,
+    /// Whether the hoop passes to the next ball player after every round.
+    pub rotate_hoop: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameEndReason {
+    /// Nobody was in the game for the idle timeout.
+    Idle,
+    /// The game ran for its maximum duration.
+    MaxDuration,
+    /// The game loop failed.
+    Failed,
+}
+
+/// What the lobby needs to know about how full a game is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameStatus {
+    pub players: u32,
+    pub hoop_free: bool,
+    pub ball_slots_free: u32,
+}
+
+/// How a game was set up by whoever created it, kept across restarts.
+#[derive(Debug, Clone)]
+pub struct GameSettings {
+    pub name: String,
+    pub code: String,
+    /// Private games can only be found by their code.
+    pub private: bool,
+    pub password: Option<GamePassword>,
+}
+
+pub struct GameEnd {
+    pub id: u32,
+    pub reason: GameEndReason,
+    /// Clients that were on their way into the game as it ended, to be placed in a new one.
+    pub orphans: Vec<Connection>,
+}
+
+impl GameEnd {
+    fn failed(id: u32) -> Self {
+        Self {
+            id,
+            reason: GameEndReason::Failed,
+            orphans: vec![],
+        }
+    }
+}
+
+pub struct GameHost {
+    id: u32,
+    settings: GameSettings,
+    connection_tx: mpsc::Sender<Connection>,
+    task: JoinHandle<GameEnd>,
+    status_rx: watch::Receiver<GameStatus>,
+}
+
+impl GameHost {
+    pub fn new(id: u32, settings: GameSettings, config: GameConfig) -> Self {
+        info!(
+            "Starting game {} ({:?}, code {}{}{})",
+            id,
+            settings.name,
+            settings.code,
+            if settings.private { ", private" } else { "" },
+            if settings.password.is_some() {
+                ", password protected"
+            } else {
+                ""
+            }
+        );
+        let (connection_tx, connection_rx) = mpsc::channel(4);
+        let (status_tx, status_rx) = watch::channel(GameStatus {
+            players: 0,
+            hoop_free: true,
+            ball_slots_free: config.max_ball_players as u32,
+        });
+        let task = tokio::spawn(async move {
+            match game_loop(connection_rx, status_tx, id, config).await {
+                Ok(end) => end,
+                Err(e) => {
+                    error!("Game loop error for game {id}: {:#}", e);
+                    GameEnd::failed(id)
+                }
+            }
+        });
+        Self {
+            id,
+            settings,
+            connection_tx,
+            task,
+            status_rx,
+        }
+    }
+
+    pub fn settings(&self) -> &GameSettings {
+        &self.settings
+    }
+
+    /// The game as the lobby lists it.
+    pub fn info(&self) -> GameInfo {
+        let status = *self.status_rx.borrow();
+        GameInfo {
+            id: self.id,
+            name: self.settings.name.clone(),
+            code: self.settings.code.clone(),
+            players: status.players,
+            hoop_free: status.hoop_free,
+            ball_slots_free: status.ball_slots_free,
+            private: self.settings.private,
+            password_required: self.settings.password.is_some(),
+        }
+    }
+
+    /// Waits for the game to end. Only to be awaited to the end once, after which the game is
+    /// done with.
+    pub async fn await_end(&mut self) -> GameEnd {
+        match (&mut self.task).await {
+            Ok(end) => end,
+            Err(e) => {
+                error!("Game {} panicked or was cancelled: {}", self.id, e);
+                GameEnd::failed(self.id)
+            }
+        }
+    }
+
+    /// Hands a client to the game, or back to the caller if the game is busy or ending.
+    pub fn new_client(&self, connection: Connection) -> Result<(), TrySendError<Connection>> {
+        self.connection_tx.try_send(connection)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientRole {
+    Hoop,
+    Ball { id: u32 },
+    Observer,
+}
+
+impl ClientRole {
+    fn establishing_message(&self, session: SessionToken) -> ToClientMessage {
+        match self {
+            ClientRole::Hoop => ToClientMessage::EstablishAsHoop { session },
+            ClientRole::Ball { id } => ToClientMessage::EstablishAsBall { id: *id, session },
+            ClientRole::Observer => ToClientMessage::EstablishAsObserver { session },
+        }
+    }
+}
+
+struct Client {
+    read: MessageReader,
+    outbox: Outbox,
+    role: ClientRole,
+    session: SessionToken,
+    /// How many commands this client sent that the server refused.
+    rejected_commands: u32,
+    /// How many inputs this client sent that a fair client couldn't have.
+    input_violations: u32,
+    /// The tick of the latest snapshot the client said it has.
+    acked_snapshot: Option<u64>,
+    /// The last hoop move the client sent since it took the hoop.
+    hoop_sequence: u32,
+}
+
+impl Client {
+    /// Counts a violation, and turns it into an error if the input was ignored altogether.
+    fn record_violation(
+        &mut self,
+        violation: InputViolation,
+        client_index: usize,
+        game_id: u32,
+        error: CommandError,
+    ) -> Result<(), CommandError> {
+        self.input_violations += 1;
+        warn!(
+            "Client {client_index} in game {game_id} input violation ({} so far): {violation:?}",
+            self.input_violations
+        );
+        match violation {
+            InputViolation::OverBudget => Ok(()),
+            InputViolation::InvalidNumber | InputViolation::BallInFlight => Err(error),
+        }
+    }
+}
+
+/// The role of a client that disconnected, kept for a while in case it reconnects.
+struct HeldRole {
+    session: SessionToken,
+    role: ClientRole,
+    until: Instant,
+}
+
+async fn read_one_client_message(
+    clients: &mut [Client],
+) -> (usize, anyhow::Result<ToServerMessage>) {
+    if clients.is_empty() {
+        let () = futures::future::pending().await;
+        unreachable!()
+    }
+    let (result, client_index, _) = select_all(
+        clients
+            .iter_mut()
+            .map(|client| Box::pin(client.read.next())),
+    )
+    .await;
+    let result = result.unwrap_or(Err(anyhow!("Client closed connection")));
+    (client_index, result)
+}
+
+/// Everything a game loop keeps track of besides its channels and timers.
+struct HostState {
+    id: u32,
+    config: GameConfig,
+    game: Game,
+    game_match: Match,
+    clients: Vec<Client>,
+    held_roles: Vec<HeldRole>,
+    /// Messages waiting to be broadcast to all clients.
+    updates: Vec<ToClientMessage>,
+    /// Changes to the game waiting to go out with the next tick.
+    pending_updates: Vec<UpdateState>,
+    /// Recent snapshots by tick, oldest first.
+    snapshots: VecDeque<(u64, GameState)>,
+    /// Balls whose players asked for the hoop this round.
+    swap_requests: Vec<u32>,
+    /// The ball whose player gets the hoop at the end of the round.
+    accepted_swap: Option<u32>,
+}
+
+impl HostState {
+    fn new(id: u32, config: GameConfig) -> Self {
+        Self {
+            id,
+            game_match: Match::new(config.rules.clone()),
+            config,
+            game: Game::default(),
+            clients: vec![],
+            held_roles: vec![],
+            updates: vec![],
+            pending_updates: vec![],
+            snapshots: VecDeque::new(),
+            swap_requests: vec![],
+            accepted_swap: None,
+        }
+    }
+
+    fn push_update(&mut self, update: UpdateState) {
+        self.pending_updates.push(update);
+    }
+
+    /// Advances the simulation by one tick, and sends out everything that changed in it.
+    fn step(&mut self) {
+        self.game.step(&mut self.pending_updates);
+        let tick = self.game.tick();
+        let (moves, events): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_updates)
+            .into_iter()
+            .partition(UpdateState::is_move);
+        self.updates.push(ToClientMessage::Tick {
+            tick,
+            updates: moves,
+        });
+        if !events.is_empty() {
+            self.updates.push(ToClientMessage::Events {
+                tick,
+                updates: events,
+            });
+        }
+        if tick.is_multiple_of(SNAPSHOT_INTERVAL) {
+            self.send_snapshots(tick);
+        }
+    }
+
+    /// Sends every client the game state as of `tick`, as the changes since the last snapshot it
+    /// acknowledged. Clients that just joined get their first state this way too.
+    fn send_snapshots(&mut self, tick: u64) {
+        let state = self.game.state().clone();
+        // Each client's snapshot has to come after the updates it sums up.
+        self.broadcast();
+        // Backwards, so that dropping a client doesn't move the ones still to go.
+        for client_index in (0..self.clients.len()).rev() {
+            let acked = self.clients[client_index].acked_snapshot;
+            let baseline = self
+                .snapshots
+                .iter()
+                .find(|(snapshot_tick, _)| Some(*snapshot_tick) == acked);
+            let (baseline, delta) = match baseline {
+                Some((baseline_tick, baseline)) => {
+                    (Some(*baseline_tick), StateDelta::between(baseline, &state))
+                }
+                None => (None, StateDelta::full(&state)),
+            };
+            let snapshot = ToClientMessage::Snapshot {
+                tick,
+                baseline,
+                delta,
+                hoop_sequence: self.clients[client_index].hoop_sequence,
+            };
+            _ = self.send_to_client(client_index, snapshot);
+        }
+        self.snapshots.push_back((tick, state));
+        if self.snapshots.len() > SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Roles taken by connected clients, or held for disconnected ones.
+    fn taken_roles(&self) -> impl Iterator<Item = ClientRole> + '_ {
+        self.clients
+            .iter()
+            .map(|client| client.role)
+            .chain(self.held_roles.iter().map(|held| held.role))
+    }
+
+    fn hoop_free(&self) -> bool {
+        !self.taken_roles().any(|role| role == ClientRole::Hoop)
+    }
+
+    fn ball_slots_free(&self) -> usize {
+        let ball_players = self
+            .taken_roles()
+            .filter(|role| matches!(role, ClientRole::Ball { .. }))
+            .count();
+        self.config.max_ball_players.saturating_sub(ball_players)
+    }
+
+    fn status(&self) -> GameStatus {
+        GameStatus {
+            players: self.clients.len() as u32,
+            hoop_free: self.hoop_free(),
+            ball_slots_free: self.ball_slots_free() as u32,
+        }
+    }
+
+    /// Picks the role for a new client, going by its preference if that role is free and
+    /// otherwise filling the hoop first. A ball is added to the game for a new shooter.
+    fn assign_role(&mut self, preferred: Option<PreferredRole>) -> ClientRole {
+        let wants_ball = match preferred {
+            Some(PreferredRole::Observer) => return ClientRole::Observer,
+            Some(PreferredRole::Ball) => true,
+            Some(PreferredRole::Hoop) | None => false,
+        };
+        if !wants_ball && self.hoop_free() {
+            return ClientRole::Hoop;
+        }
+        if self.ball_slots_free() == 0 {
+            return if self.hoop_free() {
+                ClientRole::Hoop
+            } else {
+                ClientRole::Observer
+            };
+        }
+        let (id, update) = self.game.add_ball();
+        self.push_update(update);
+        ClientRole::Ball { id }
+    }
+
+    /// Takes back the role of the given session, if it's held or still attached to a connection
+    /// the client has since abandoned.
+    fn resume_role(&mut self, session: SessionToken) -> Option<ClientRole> {
+        if let Some(index) = self.held_roles.iter().position(|h| h.session == session) {
+            return Some(self.held_roles.remove(index).role);
+        }
+        let index = self.clients.iter().position(|c| c.session == session)?;
+        info!(
+            "Client {index} in game {} replaced by a reconnection",
+            self.id
+        );
+        Some(self.clients.remove(index).role)
+    }
+
+    fn add_client(&mut self, connection: Connection) {
+        let resumed = connection
+            .session
+            .and_then(|session| Some((session, self.resume_role(session)?)));
+        let (session, role) = match resumed {
+            Some(resumed) => resumed,
+            None => (
+                SessionToken(rand::random()),
+                self.assign_role(connection.preferred_role),
+            ),
+        };
+        let client_index = self.clients.len();
+        info!(
+            "Client {} in game {} is {:?}{}",
+            client_index,
+            self.id,
+            role,
+            if resumed.is_some() { " (resumed)" } else { "" }
+        );
+        self.clients.push(Client {
+            read: connection.read,
+            outbox: Outbox::new(connection.write),
+            role,
+            session,
+            rejected_commands: 0,
+            input_violations: 0,
+            acked_snapshot: None,
+            hoop_sequence: 0,
+        });
+        let phase = self.phase_message(Instant::now());
+        // The game state comes with the next snapshot.
+        for message in [role.establishing_message(session), phase] {
+            if !self.send_to_client(client_index, message) {
+                break;
+            }
+        }
+    }
+
+    /// Gives up a role for good, taking its ball (if any) or the hoop's score out of the game.
+    fn release_role(&mut self, role: ClientRole) {
+        let update = match role {
+            ClientRole::Ball { id } => self.game.remove_ball(id),
+            ClientRole::Hoop => self.game.clear_hoop_score(),
+            ClientRole::Observer => None,
+        };
+        if let Some(update) = update {
+            self.push_update(update);
+        }
+    }
+
+    /// Drops a client. Unless it's being kicked out, its role is held for it to reconnect to.
+    fn remove_client(&mut self, client_index: usize, hold_role: bool) {
+        let client = self.clients.remove(client_index);
+        if !hold_role || client.role == ClientRole::Observer {
+            self.release_role(client.role);
+            return;
+        }
+        self.held_roles.push(HeldRole {
+            session: client.session,
+            role: client.role,
+            until: Instant::now() + self.config.reconnect_grace,
+        });
+    }
+
+    fn next_held_role_expiry(&self) -> Option<Instant> {
+        self.held_roles.iter().map(|held| held.until).min()
+    }
+
+    fn release_expired_roles(&mut self) {
+        let now = Instant::now();
+        let (expired, held): (Vec<_>, Vec<_>) = self
+            .held_roles
+            .drain(..)
+            .partition(|held| held.until <= now);
+        self.held_roles = held;
+        for held in expired {
+            info!("Game {} releasing unclaimed {:?}", self.id, held.role);
+            self.release_role(held.role);
+        }
+    }
+
+    fn client_with_role(&self, role: ClientRole) -> Option<usize> {
+        self.clients.iter().position(|client| client.role == role)
+    }
+
+    fn phase_message(&self, now: Instant) -> ToClientMessage {
+        ToClientMessage::PhaseChanged {
+            phase: self.game_match.phase(),
+            rounds: self.game_match.rules().rounds,
+            remaining_secs: self
+                .game_match
+                .remaining(now)
+                .map(|remaining| remaining.as_secs_f32()),
+        }
+    }
+
+    /// Moves the match on as far as it's ready to go.
+    fn update_match(&mut self) {
+        let now = Instant::now();
+        loop {
+            // A held role still counts, so a player dropping for a moment doesn't stop the match.
+            let ready = !self.hoop_free()
+                && self
+                    .taken_roles()
+                    .any(|role| matches!(role, ClientRole::Ball { .. }));
+            let inputs = MatchInputs {
+                ready,
+                state: self.game.state(),
+                balls_in_flight: self.game.balls_in_flight(),
+            };
+            let Some(phase) = self.game_match.next_phase(&inputs, now) else {
+                return;
+            };
+            let previous = self.game_match.phase();
+            info!("Game {} match phase: {phase:?}", self.id);
+            if let MatchPhase::Playing { .. } = previous {
+                self.end_round();
+            }
+            match phase {
+                MatchPhase::Countdown { round: 1 } if previous == MatchPhase::WaitingForPlayers => {
+                    let update = self.game.reset_scores();
+                    self.push_update(update);
+                }
+                MatchPhase::MatchOver => {
+                    let (standings, winner) = standings(self.game.state());
+                    self.updates.push(ToClientMessage::MatchOver {
+                        standings,
+                        hoop_nopes: self.game.state().nopes,
+                        winner,
+                    });
+                }
+                _ => {}
+            }
+            self.game_match.enter(phase, now);
+            self.updates.push(self.phase_message(now));
+        }
+    }
+
+    /// Swaps roles as asked or as the rotation goes, ready for the next round.
+    fn end_round(&mut self) {
+        self.swap_requests.clear();
+        let swap = match self.accepted_swap.take() {
+            Some(ball_id) => Some(ball_id),
+            None if self.config.rotate_hoop => self.next_hoop(),
+            None => None,
+        };
+        if let Some(ball_id) = swap {
+            self.give_hoop_to(ball_id);
+        }
+    }
+
+    /// The ball of the player after the hoop, in the order they joined.
+    fn next_hoop(&self) -> Option<u32> {
+        let start = self.client_with_role(ClientRole::Hoop).map_or(0, |i| i + 1);
+        let count = self.clients.len();
+        (0..count)
+            .map(|offset| self.clients[(start + offset) % count].role)
+            .find_map(|role| match role {
+                ClientRole::Ball { id } => Some(id),
+                _ => None,
+            })
+    }
+
+    /// Makes the player of a ball the hoop, handing the ball to the old hoop if there is one.
+    fn give_hoop_to(&mut self, ball_id: u32) {
+        let ball_role = ClientRole::Ball { id: ball_id };
+        let Some(new_hoop) = self.client_with_role(ball_role) else {
+            return;
+        };
+        info!(
+            "Client {new_hoop} in game {} takes the hoop from ball {ball_id}",
+            self.id
+        );
+        let old_hoop = self.client_with_role(ClientRole::Hoop);
+        // Scores go with the players, not the roles.
+        let update = self.game.swap_scores(ball_id);
+        self.push_update(update);
+        self.clients[new_hoop].role = ClientRole::Hoop;
+        // Its moves are counted again from the start, as for a new hoop.
+        self.clients[new_hoop].hoop_sequence = 0;
+        if let Some(old_hoop) = old_hoop {
+            self.clients[old_hoop].role = ball_role;
+        } else if let Some(held) = self
+            .held_roles
+            .iter_mut()
+            .find(|held| held.role == ClientRole::Hoop)
+        {
+            held.role = ball_role;
+        } else {
+            self.release_role(ball_role);
+        }
+        let changed =
+            [Some(new_hoop), old_hoop].map(|index| index.map(|i| self.clients[i].session));
+        for session in changed.into_iter().flatten() {
+            self.send_to_session(session, |client| {
+                client.role.establishing_message(client.session)
+            });
+        }
+        self.updates.push(ToClientMessage::RolesSwapped { ball_id });
+    }
+
+    /// Queues a message for the client with a session, if it's still there.
+    fn send_to_session(
+        &mut self,
+        session: SessionToken,
+        message: impl FnOnce(&Client) -> ToClientMessage,
+    ) {
+        if let Some(index) = self.clients.iter().position(|c| c.session == session) {
+            let message = message(&self.clients[index]);
+            _ = self.send_to_client(index, message);
+        }
+    }
+
+    /// Queues a message for one client, dropping the client if it can't take it. Returns whether
+    /// the client is still there.
+    fn send_to_client(&mut self, client_index: usize, message: ToClientMessage) -> bool {
+        let Err(e) = self.clients[client_index].outbox.send(message) else {
+            return true;
+        };
+        info!(
+            "Client {client_index} in game {} send error (terminating): {e}",
+            self.id
+        );
+        self.remove_client(client_index, true);
+        false
+    }
+
+    /// Queues the pending updates for every client. Clients that can't take them are dropped, and
+    /// the updates that causes are sent out in turn.
+    fn broadcast(&mut self) {
+        while !self.updates.is_empty() {
+            let mut failed = vec![];
+            for (client_index, client) in self.clients.iter().enumerate() {
+                for update in &self.updates {
+                    trace!("Sending update to client {client_index}: {update:?}");
+                    if let Err(e) = client.outbox.send(update.clone()) {
+                        info!(
+                            "Client {client_index} in game {} send error (terminating): {e}",
+                            self.id
+                        );
+                        failed.push(client_index);
+                        break;
+                    }
+                }
+            }
+            self.updates.clear();
+            for client_index in failed.into_iter().rev() {
+                self.remove_client(client_index, true);
+            }
+        }
+    }
+
+    fn handle_client_result(
+        &mut self,
+        client_index: usize,
+        result: anyhow::Result<ToServerMessage>,
+    ) {
+        let message = match result {
+            Ok(message) => message,
+            Err(e) => {
+                info!(
+                    "Client {} in game {} read error (terminating): {:#}",
+                    client_index, self.id, e
+                );
+                self.remove_client(client_index, true);
+                return;
+            }
+        };
+        if let Err(error) = self.handle_client_message(client_index, message) {
+            let client = &mut self.clients[client_index];
+            client.rejected_commands += 1;
+            warn!(
+                "Client {} in game {} sent a rejected command ({} so far): {}",
+                client_index, self.id, client.rejected_commands, error
+            );
+            let rejection = ToClientMessage::CommandRejected { error };
+            _ = self.send_to_client(client_index, rejection);
+        }
+    }
+
+    /// Applies a client's command to the game, unless the client's role doesn't allow it.
+    fn handle_client_message(
+        &mut self,
+        client_index: usize,
+        message: ToServerMessage,
+    ) -> Result<(), CommandError> {
+        let game_id = self.id;
+        let role = self.clients[client_index].role;
+        match message {
+            ToServerMessage::MoveHoop {
+                direction,
+                seconds_pressed,
+                sequence,
+            } => {
+                if role != ClientRole::Hoop {
+                    return Err(CommandError::NotTheHoop);
+                }
+                trace!("Client {client_index} in game {game_id} moved hoop: {direction:?} ({sequence})");
+                let result = self.game.move_hoop(direction, seconds_pressed);
+                self.clients[client_index].hoop_sequence = sequence;
+                if result != Err(InputViolation::InvalidNumber) {
+                    self.push_update(UpdateState::MoveHoop {
+                        x: self.game.state().hoop_x,
+                        sequence,
+                    });
+                }
+                if let Err(violation) = result {
+                    self.clients[client_index].record_violation(
+                        violation,
+                        client_index,
+                        game_id,
+                        CommandError::InvalidInput,
+                    )?;
+                }
+            }
+            ToServerMessage::ShootBall {
+                id,
+                angle,
+                seconds_pressed,
+            } => {
+                if role != (ClientRole::Ball { id }) {
+                    return Err(CommandError::NotYourBall { id });
+                }
+                self.game_match.check_shot(id)?;
+                trace!("Client {client_index} in game {game_id} shot ball: {id:?}");
+                match self.game.shoot_ball(id, angle, seconds_pressed) {
+                    Ok(()) => self.game_match.record_shot(id),
+                    Err(violation) => {
+                        let error = match violation {
+                            InputViolation::BallInFlight => CommandError::BallInFlight { id },
+                            _ => CommandError::InvalidInput,
+                        };
+                        self.clients[client_index].record_violation(
+                            violation,
+                            client_index,
+                            game_id,
+                            error,
+                        )?;
+                    }
+                }
+            }
+            ToServerMessage::RequestRoleSwap => {
+                let ClientRole::Ball { id } = role else {
+                    return Err(CommandError::NotABall);
+                };
+                info!("Client {client_index} in game {game_id} asked for the hoop");
+                if self.hoop_free() {
+                    self.accepted_swap = Some(id);
+                    let answer = ToClientMessage::RoleSwapAnswered { accepted: true };
+                    _ = self.send_to_client(client_index, answer);
+                    return Ok(());
+                }
+                if !self.swap_requests.contains(&id) {
+                    self.swap_requests.push(id);
+                }
+                if let Some(hoop) = self.client_with_role(ClientRole::Hoop) {
+                    let request = ToClientMessage::RoleSwapRequested { ball_id: id };
+                    _ = self.send_to_client(hoop, request);
+                }
+            }
+            ToServerMessage::AnswerRoleSwap { ball_id, accept } => {
+                if role != ClientRole::Hoop {
+                    return Err(CommandError::NotTheHoop);
+                }
+                let Some(request) = self.swap_requests.iter().position(|&id| id == ball_id) else {
+                    return Err(CommandError::NoSwapRequested { ball_id });
+                };
+                self.swap_requests.remove(request);
+                info!("Client {client_index} in game {game_id} answered ball {ball_id}'s swap request: {accept}");
+                if accept {
+                    self.accepted_swap = Some(ball_id);
+                }
+                if let Some(requester) = self.client_with_role(ClientRole::Ball { id: ball_id }) {
+                    let answer = ToClientMessage::RoleSwapAnswered { accepted: accept };
+                    _ = self.send_to_client(requester, answer);
+                }
+            }
+            ToServerMessage::AckSnapshot { tick } => {
+                // Acknowledging a snapshot that's gone, or older than one already acknowledged,
+                // doesn't help.
+                let client = &mut self.clients[client_index];
+                let known = self
+                    .snapshots
+                    .iter()
+                    .any(|(snapshot_tick, _)| *snapshot_tick == tick);
+                if known && client.acked_snapshot < Some(tick) {
+                    client.acked_snapshot = Some(tick);
+                }
+            }
+            ToServerMessage::Hello { .. } | ToServerMessage::AuthResponse { .. } => {
+                error!("Client {client_index} in game {game_id} sent a handshake message after the handshake - terminating");
+                self.remove_client(client_index, false);
+            }
+            ToServerMessage::ListGames
+            | ToServerMessage::CreateGame { .. }
+            | ToServerMessage::JoinGame { .. } => {
+                error!("Client {client_index} in game {game_id} sent a lobby message from a game - terminating");
+                self.remove_client(client_index, false);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Stops taking clients, collecting any that were already sent.
+fn end_game(
+    mut connection_rx: mpsc::Receiver<Connection>,
+    id: u32,
+    reason: GameEndReason,
+) -> GameEnd {
+    info!("Game {id} ending: {reason:?}");
+    connection_rx.close();
+    let mut orphans = vec![];
+    while let Ok(connection) = connection_rx.try_recv() {
+        orphans.push(connection);
+    }
+    GameEnd {
+        id,
+        reason,
+        orphans,
+    }
+}
+
+async fn game_loop(
+    mut connection_rx: mpsc::Receiver<Connection>,
+    status_tx: watch::Sender<GameStatus>,
+    id: u32,
+    config: GameConfig,
+) -> anyhow::Result<GameEnd> {
+    let mut frame_timer = tokio::time::interval(TICK_DURATION);
+    frame_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut last_frame_time = Instant::now();
+    // Time that's passed but hasn't been simulated yet.
+    let mut unsimulated = Duration::ZERO;
+    let end_deadline = config
+        .max_duration
+        .map(|duration| last_frame_time + duration);
+    let mut idle_deadline = Some(last_frame_time + config.idle_timeout);
+    let mut host = HostState::new(id, config);
+    loop {
+        tokio::select! {
+            () = wait_until(idle_deadline) => {
+                return Ok(end_game(connection_rx, id, GameEndReason::Idle));
+            }
+            () = wait_until(end_deadline) => {
+                return Ok(end_game(connection_rx, id, GameEndReason::MaxDuration));
+            }
+            () = wait_until(host.game_match.deadline()) => {}
+            () = wait_until(host.next_held_role_expiry()) => {
+                host.release_expired_roles();
+            }
+            new_connection = connection_rx.recv() => {
+                let connection = new_connection.context("Failed to receive connection")?;
+                host.add_client(connection);
+            }
+            (client_index, result) = read_one_client_message(&mut host.clients) => {
+                host.handle_client_result(client_index, result);
+            }
+            _ = frame_timer.tick() => {
+                let now = Instant::now();
+                unsimulated += now - last_frame_time;
+                last_frame_time = now;
+                let mut ticks = 0;
+                while unsimulated >= TICK_DURATION {
+                    if ticks == MAX_CATCH_UP_TICKS {
+                        warn!("Game {id} fell {unsimulated:?} behind at tick {}", host.game.tick());
+                        unsimulated = Duration::ZERO;
+                        break;
+                    }
+                    unsimulated -= TICK_DURATION;
+                    host.step();
+                    ticks += 1;
+                }
+            }
+        }
+        host.update_match();
+        host.broadcast();
+        status_tx.send_if_modified(|status| {
+            let new_status = host.status();
+            let modified = *status != new_status;
+            *status = new_status;
+            modified
+        });
+        if !host.clients.is_empty() {
+            idle_deadline = None;
+        } else if idle_deadline.is_none() {
+            info!("Game {id} has no clients left");
+            idle_deadline = Some(Instant::now() + host.config.idle_timeout);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use futures::StreamExt;
+use nope_the_hoop_proto::{
+    auth::{ChallengeResponse, GamePassword, Nonce},
+    lobby::{GameInfo, GameRef, LobbyError},
+    message::{ToClientMessage, ToServerMessage},
+};
+use rand::seq::SliceRandom;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    host::Connection,
+    transport::{write_message, MessageReader, MessageWriter},
+};
+
+/// The longest game name the lobby accepts, in characters.
+const MAX_GAME_NAME_LENGTH: usize = 32;
+/// Long enough that a private game's code can't be guessed.
+const JOIN_CODE_LENGTH: usize = 8;
+/// Join codes leave out characters that are easy to mix up when read out.
+const JOIN_CODE_CHARACTERS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// What a client in the lobby asks of the server's games.
+pub(crate) enum LobbyRequest {
+    ListGames {
+        reply: oneshot::Sender<Vec<GameInfo>>,
+    },
+    CreateGame {
+        name: String,
+        private: bool,
+        password: Option<GamePassword>,
+        reply: oneshot::Sender<Result<GameInfo, LobbyError>>,
+    },
+    /// Hands the connection to the game, or back with the reason it couldn't be.
+    JoinGame {
+        game: GameRef,
+        /// The connection's password nonce, and the client's answer to it.
+        password_nonce: Nonce,
+        password: Option<ChallengeResponse>,
+        connection: Connection,
+        reply: oneshot::Sender<Result<(), (LobbyError, Connection)>>,
+    },
+}
+
+pub(crate) fn new_join_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..JOIN_CODE_LENGTH)
+        .map(|_| *JOIN_CODE_CHARACTERS.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+/// Normalizes a name for a new game, if it's acceptable.
+pub(crate) fn valid_game_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    let length = name.chars().count();
+    (1..=MAX_GAME_NAME_LENGTH)
+        .contains(&length)
+        .then(|| name.to_owned())
+}
+
+/// Serves a client that finished its handshake until it joins a game.
+pub(crate) async fn lobby_loop(
+    mut read: MessageReader,
+    mut write: Box<dyn MessageWriter>,
+    password_nonce: Nonce,
+    lobby_tx: mpsc::Sender<LobbyRequest>,
+) -> anyhow::Result<()> {
+    loop {
+        let Some(client_message) = read.next().await else {
+            anyhow::bail!("Client closed connection in the lobby");
+        };
+        let reply = match client_message? {
+            ToServerMessage::ListGames => {
+                let (reply, games) = oneshot::channel();
+                lobby_tx.send(LobbyRequest::ListGames { reply }).await?;
+                ToClientMessage::GameList {
+                    games: games.await?,
+                }
+            }
+            ToServerMessage::CreateGame {
+                name,
+                private,
+                password,
+            } => {
+                let Some(name) = valid_game_name(&name) else {
+                    let error = LobbyError::InvalidName;
+                    write_message(&mut *write, ToClientMessage::LobbyError { error }).await?;
+                    continue;
+                };
+                let (reply, game) = oneshot::channel();
+                lobby_tx
+                    .send(LobbyRequest::CreateGame {
+                        name,
+                        private,
+                        password,
+                        reply,
+                    })
+                    .await?;
+                match game.await? {
+                    Ok(game) => ToClientMessage::GameCreated { game },
+                    Err(error) => ToClientMessage::LobbyError { error },
+                }
+            }
+            ToServerMessage::JoinGame {
+                game,
+                session,
+                password,
+                preferred_role,
+            } => {
+                let (reply, joined) = oneshot::channel();
+                let connection = Connection {
+                    read,
+                    write,
+                    session,
+                    preferred_role,
+                };
+                lobby_tx
+                    .send(LobbyRequest::JoinGame {
+                        game,
+                        password_nonce,
+                        password,
+                        connection,
+                        reply,
+                    })
+                    .await?;
+                let Err((error, connection)) = joined.await? else {
+                    return Ok(());
+                };
+                (read, write) = (connection.read, connection.write);
+                ToClientMessage::LobbyError { error }
+            }
+            message => anyhow::bail!("Expected a lobby message from client - got: {:?}", message),
+        };
+        write_message(&mut *write, reply).await?;
+    }
+}
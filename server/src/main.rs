@@ -1,17 +1,52 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
 
 use anyhow::Context;
 use clap::Parser;
 use futures::{future::select_all, StreamExt};
-use host::ServerMessageStream;
-use nope_the_hoop_proto::{message::ToServerMessage, stream::MessageStream};
-use tokio::net::TcpListener;
-use tracing::info;
+use lobby::{lobby_loop, new_join_code, LobbyRequest};
+use nope_the_hoop_proto::{
+    auth::{ChallengeResponse, Nonce, PassphraseHash},
+    lobby::{GameRef, LobbyError},
+    message::{
+        RejectReason, ToClientMessage, ToServerMessage, CAPABILITIES, CAPABILITY_PASSPHRASE_AUTH,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    tls::{self, CertificatePin},
+    udp_stream::{UdpListener, UdpReader, UdpWriter},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, error::TrySendError},
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
 
-use crate::host::GameHost;
+use crate::{
+    host::{Connection, GameConfig, GameEnd, GameHost, GameSettings},
+    rules::MatchRules,
+    transport::{write_message, MessageReader, MessageWriter},
+};
 
 mod host;
-mod sim;
+mod lobby;
+mod outbox;
+mod rules;
+mod transport;
+
+/// How long to wait before accepting again after a failed accept, at first and at most.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a TLS or WebSocket handshake can take. Longer than the hello gets, since it can come
+/// through a proxy.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often clients waiting on a busy game are offered to it again.
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Parser)]
 #[command(
@@ -24,9 +59,113 @@ struct Args {
     #[arg(short, long, default_value_t = 7434)]
     port: u16,
 
+    /// A port to also take WebSocket connections on, as from browsers or through a proxy.
+    #[arg(long)]
+    ws_port: Option<u16>,
+
+    /// A port to also take connections over UDP on.
+    #[arg(long)]
+    udp_port: Option<u16>,
+
     /// The address to bind to.
     #[arg(long, default_value = "127.0.0.1")]
     bind_address: String,
+
+    /// How many clients in a game can shoot balls; any more join as observers.
+    #[arg(long, default_value_t = 8)]
+    max_ball_players: usize,
+
+    /// How many seconds a disconnected client's role is kept for it to reconnect to.
+    #[arg(long, default_value_t = 10)]
+    reconnect_grace_secs: u64,
+
+    /// How many seconds a game lives on after its last client leaves.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout_secs: u64,
+
+    /// How many rounds make a match.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    rounds: u32,
+
+    /// How many shots each ball player gets per round.
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    shots_per_round: u32,
+
+    /// The most seconds a round lasts. Players trade roles between rounds.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    round_secs: u64,
+
+    /// A score that wins the match right away.
+    #[arg(long)]
+    points_to_win: Option<u32>,
+
+    /// How many seconds the countdown before a round, and the break after one, last.
+    #[arg(long, default_value_t = 3)]
+    countdown_secs: u64,
+
+    /// How many seconds the results of a match are shown before the next one.
+    #[arg(long, default_value_t = 10)]
+    match_over_secs: u64,
+
+    /// Pass the hoop to the next player after every round.
+    #[arg(long)]
+    rotate_hoop: bool,
+
+    /// The most games that can be running at once. Clients can't create any more until some end.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    max_games: u32,
+
+    /// The most seconds a game can last, if limited.
+    #[arg(long)]
+    max_game_duration_secs: Option<u64>,
+
+    /// A passphrase clients must prove they know before joining a game.
+    #[arg(long)]
+    passphrase: Option<String>,
+
+    /// A file whose contents (minus trailing whitespace) are used as the passphrase.
+    #[arg(long, conflicts_with = "passphrase")]
+    passphrase_file: Option<PathBuf>,
+
+    /// A PEM file with the certificate, or chain, to take TLS connections with instead of plain
+    /// ones on `--port`.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// A PEM file with the private key for `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+impl Args {
+    fn passphrase_hash(&self) -> anyhow::Result<Option<PassphraseHash>> {
+        if let Some(passphrase) = &self.passphrase {
+            return Ok(Some(PassphraseHash::new(passphrase)));
+        }
+        let Some(path) = &self.passphrase_file else {
+            return Ok(None);
+        };
+        let passphrase = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read passphrase file {}", path.display()))?;
+        Ok(Some(PassphraseHash::new(passphrase.trim_end())))
+    }
+
+    fn tls_acceptor(&self) -> anyhow::Result<Option<TlsAcceptor>> {
+        let (Some(cert_path), Some(key_path)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+        let certificates = tls::load_certificates(cert_path)?;
+        // For clients of a self-signed server to pin.
+        info!(
+            "TLS certificate pin: {}",
+            CertificatePin::of(&certificates[0])
+        );
+        let key = tls::load_private_key(key_path)?;
+        Ok(Some(TlsAcceptor::from(tls::server_config(
+            certificates,
+            key,
+        )?)))
+    }
 }
 
 #[tokio::main]
@@ -34,56 +173,449 @@ async fn main() {
     let _guard =
         tracing::subscriber::set_global_default(tracing_subscriber::fmt::Subscriber::new());
     let args = Args::parse();
+    let passphrase_hash = args.passphrase_hash().unwrap();
+    let tls_acceptor = args.tls_acceptor().unwrap();
+    let game_config = GameConfig {
+        max_ball_players: args.max_ball_players,
+        idle_timeout: Duration::from_secs(args.idle_timeout_secs),
+        max_duration: args.max_game_duration_secs.map(Duration::from_secs),
+        reconnect_grace: Duration::from_secs(args.reconnect_grace_secs),
+        rules: MatchRules {
+            rounds: args.rounds,
+            shots_per_round: args.shots_per_round,
+            round_time: Duration::from_secs(args.round_secs),
+            points_to_win: args.points_to_win,
+            countdown: Duration::from_secs(args.countdown_secs),
+            match_over_time: Duration::from_secs(args.match_over_secs),
+        },
+        rotate_hoop: args.rotate_hoop,
+    };
     let listener = TcpListener::bind(&format!("{}:{}", args.bind_address, args.port))
         .await
         .unwrap();
     info!("Listening on {}", listener.local_addr().unwrap());
+    let ws_listener = match args.ws_port {
+        Some(port) => {
+            let listener = TcpListener::bind(&format!("{}:{}", args.bind_address, port))
+                .await
+                .unwrap();
+            info!(
+                "Listening for WebSocket on {}",
+                listener.local_addr().unwrap()
+            );
+            Some(listener)
+        }
+        None => None,
+    };
+    let mut udp_listener = match args.udp_port {
+        Some(port) => {
+            let listener = UdpListener::bind(&format!("{}:{}", args.bind_address, port))
+                .await
+                .unwrap();
+            info!("Listening for UDP on {}", listener.local_addr());
+            Some(listener)
+        }
+        None => None,
+    };
     let mut games: HashMap<u32, GameHost> = HashMap::new();
+    // Clients that tried to join games too busy to take them yet, or that were ending, to join
+    // the fresh ones in their place.
+    let mut waiting_to_join: HashMap<u32, VecDeque<Connection>> = HashMap::new();
+    let mut join_retry = tokio::time::interval(JOIN_RETRY_INTERVAL);
+    join_retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut next_game_id = 1;
+    let (lobby_tx, mut lobby_rx) = mpsc::channel::<LobbyRequest>(16);
+    let mut accept_backoff = MIN_ACCEPT_BACKOFF;
+    // After a failed accept, TCP and WebSocket connections wait until then, and nothing else does.
+    let mut resume_accepting = Instant::now();
 
     loop {
+        let accepting = Instant::now() >= resume_accepting;
         tokio::select! {
-            result = listener.accept() => {
-                let (stream, addr) = result.expect("Accepting connection");
-                info!("Accepted connection from {}", addr);
-                let (read, write) = stream.into_split();
-                let mut read = MessageStream::new(read);
-                let game_id = match process_hello(&mut read).await {
-                    Ok(game_id) => game_id,
+            result = accept_tcp(&listener, ws_listener.as_ref()), if accepting => {
+                let (stream, addr, websocket) = match result {
+                    Ok(accepted) => accepted,
                     Err(e) => {
-                        info!("Connection from {} failed on hello: {:#}", addr, e);
-                        return;
+                        // Usually running out of file descriptors, which may pass once some clients leave.
+                        error!("Failed to accept connection (retrying in {:?}): {}", accept_backoff, e);
+                        resume_accepting = Instant::now() + accept_backoff;
+                        accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
                     }
                 };
-                let game = games.entry(game_id).or_insert_with(|| GameHost::new(game_id));
-                game.new_client(read, write).await;
+                accept_backoff = MIN_ACCEPT_BACKOFF;
+                if websocket {
+                    info!("Accepted WebSocket connection from {}", addr);
+                    tokio::spawn(serve_websocket(stream, addr, passphrase_hash, lobby_tx.clone()));
+                } else {
+                    info!("Accepted connection from {}", addr);
+                    let tls_acceptor = tls_acceptor.clone();
+                    tokio::spawn(serve_tcp(stream, addr, tls_acceptor, passphrase_hash, lobby_tx.clone()));
+                }
+            }
+            Some((read, write, addr)) = accept_udp(udp_listener.as_mut()) => {
+                info!("Accepted UDP connection from {}", addr);
+                let (read, write) = transport::udp(read, write);
+                tokio::spawn(serve(read, write, addr, passphrase_hash, lobby_tx.clone()));
+            }
+            Some(request) = lobby_rx.recv() => {
+                handle_lobby_request(&mut games, &mut waiting_to_join, &mut next_game_id, request, &game_config, args.max_games as usize);
             }
-            ended_game = await_game_end(&mut games) => {
-                games.remove(&ended_game);
+            end = await_game_end(&mut games) => {
+                handle_game_end(&mut games, &mut waiting_to_join, end, &game_config);
+            }
+            _ = tokio::time::sleep_until(resume_accepting), if !accepting => {}
+            _ = join_retry.tick(), if !waiting_to_join.is_empty() => {
+                let game_ids: Vec<_> = waiting_to_join.keys().copied().collect();
+                for game_id in game_ids {
+                    join_game(&games, &mut waiting_to_join, game_id, vec![]);
+                }
             }
         }
     }
 }
 
-async fn await_game_end(games: &mut HashMap<u32, GameHost>) -> u32 {
+/// Accepts from the listener, or the WebSocket one if there is one, saying whether the connection
+/// came in over WebSocket.
+async fn accept_tcp(
+    listener: &TcpListener,
+    ws_listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr, bool)> {
+    let Some(ws_listener) = ws_listener else {
+        let (stream, addr) = listener.accept().await?;
+        return Ok((stream, addr, false));
+    };
+    tokio::select! {
+        result = listener.accept() => result.map(|(stream, addr)| (stream, addr, false)),
+        result = ws_listener.accept() => result.map(|(stream, addr)| (stream, addr, true)),
+    }
+}
+
+async fn accept_udp(
+    listener: Option<&mut UdpListener<ToClientMessage, ToServerMessage>>,
+) -> Option<(
+    UdpReader<ToServerMessage>,
+    UdpWriter<ToClientMessage>,
+    SocketAddr,
+)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Takes a new connection through its handshake and the lobby, until it joins a game.
+async fn serve(
+    mut read: MessageReader,
+    mut write: Box<dyn MessageWriter>,
+    addr: SocketAddr,
+    passphrase_hash: Option<PassphraseHash>,
+    lobby_tx: mpsc::Sender<LobbyRequest>,
+) {
+    let hello = process_hello(&mut read, &mut *write, passphrase_hash.as_ref()).await;
+    let password_nonce = match hello {
+        Ok(password_nonce) => password_nonce,
+        Err(e) => {
+            info!("Connection from {} failed on hello: {:#}", addr, e);
+            return;
+        }
+    };
+    if let Err(e) = lobby_loop(read, write, password_nonce, lobby_tx).await {
+        info!("Connection from {} left the lobby: {:#}", addr, e);
+    }
+}
+
+/// Serves a connection to `--port`, after a TLS handshake if the server takes TLS.
+async fn serve_tcp(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+    passphrase_hash: Option<PassphraseHash>,
+    lobby_tx: mpsc::Sender<LobbyRequest>,
+) {
+    let Some(tls_acceptor) = tls_acceptor else {
+        let (read, write) = transport::stream(stream);
+        return serve(read, write, addr, passphrase_hash, lobby_tx).await;
+    };
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream))
+        .await
+        .context("Timed out on the TLS handshake")
+        .and_then(|result| result.context("TLS handshake failed"));
+    match stream {
+        Ok(stream) => {
+            let (read, write) = transport::stream(stream);
+            serve(read, write, addr, passphrase_hash, lobby_tx).await;
+        }
+        Err(e) => info!("TLS connection from {} failed: {:#}", addr, e),
+    }
+}
+
+async fn serve_websocket(
+    stream: TcpStream,
+    addr: SocketAddr,
+    passphrase_hash: Option<PassphraseHash>,
+    lobby_tx: mpsc::Sender<LobbyRequest>,
+) {
+    let websocket = tokio::time::timeout(HANDSHAKE_TIMEOUT, transport::websocket(stream))
+        .await
+        .context("Timed out on the WebSocket handshake")
+        .and_then(|result| result);
+    match websocket {
+        Ok((read, write)) => serve(read, write, addr, passphrase_hash, lobby_tx).await,
+        Err(e) => info!("WebSocket connection from {} failed: {:#}", addr, e),
+    }
+}
+
+async fn await_game_end(games: &mut HashMap<u32, GameHost>) -> GameEnd {
     if games.is_empty() {
         let () = futures::future::pending().await;
         unreachable!()
     }
-    let (ended_game, _, _) =
-        select_all(games.values_mut().map(|game| Box::pin(game.await_end()))).await;
-    ended_game
+    let (end, _, _) = select_all(games.values_mut().map(|game| Box::pin(game.await_end()))).await;
+    end
 }
 
-async fn process_hello(read: &mut ServerMessageStream) -> anyhow::Result<u32> {
+/// Drops an ended game, or starts it afresh if there are clients on their way into it.
+fn handle_game_end(
+    games: &mut HashMap<u32, GameHost>,
+    waiting_to_join: &mut HashMap<u32, VecDeque<Connection>>,
+    end: GameEnd,
+    config: &GameConfig,
+) {
+    info!("Game {} ended: {:?}", end.id, end.reason);
+    let game = games.remove(&end.id).expect("Ended game was running");
+    let waiting = waiting_to_join.remove(&end.id).unwrap_or_default();
+    let joining: Vec<_> = end.orphans.into_iter().chain(waiting).collect();
+    if joining.is_empty() {
+        return;
+    }
+    let restarted = GameHost::new(end.id, game.settings().clone(), config.clone());
+    games.insert(end.id, restarted);
+    join_game(games, waiting_to_join, end.id, joining);
+}
+
+fn handle_lobby_request(
+    games: &mut HashMap<u32, GameHost>,
+    waiting_to_join: &mut HashMap<u32, VecDeque<Connection>>,
+    next_game_id: &mut u32,
+    request: LobbyRequest,
+    config: &GameConfig,
+    max_games: usize,
+) {
+    match request {
+        LobbyRequest::ListGames { reply } => {
+            let mut list: Vec<_> = games
+                .values()
+                .filter(|game| !game.settings().private)
+                .map(GameHost::info)
+                .collect();
+            list.sort_by_key(|game| game.id);
+            _ = reply.send(list);
+        }
+        LobbyRequest::CreateGame {
+            name,
+            private,
+            password,
+            reply,
+        } => {
+            if games.len() >= max_games {
+                info!("Refused to create game {:?}: {} running", name, games.len());
+                _ = reply.send(Err(LobbyError::TooManyGames));
+                return;
+            }
+            let id = *next_game_id;
+            *next_game_id += 1;
+            let settings = GameSettings {
+                name,
+                code: unused_join_code(games),
+                private,
+                password,
+            };
+            let game = GameHost::new(id, settings, config.clone());
+            _ = reply.send(Ok(game.info()));
+            games.insert(id, game);
+        }
+        LobbyRequest::JoinGame {
+            game,
+            password_nonce,
+            password,
+            connection,
+            reply,
+        } => match admit(games, &game, &password_nonce, password.as_ref()) {
+            Ok(id) => {
+                _ = reply.send(Ok(()));
+                join_game(games, waiting_to_join, id, vec![connection]);
+            }
+            Err(error) => {
+                info!("Refused to join game {}: {}", game, error);
+                _ = reply.send(Err((error, connection)));
+            }
+        },
+    }
+}
+
+/// Finds the game a client asked to join, if it exists and the client knows its password.
+fn admit(
+    games: &HashMap<u32, GameHost>,
+    game: &GameRef,
+    password_nonce: &Nonce,
+    password: Option<&ChallengeResponse>,
+) -> Result<u32, LobbyError> {
+    let id = find_game(games, game).ok_or_else(|| LobbyError::NoSuchGame { game: game.clone() })?;
+    if let Some(expected) = &games[&id].settings().password {
+        if !password.is_some_and(|password| expected.hash.verify(password_nonce, password)) {
+            return Err(LobbyError::WrongGamePassword {
+                game: game.clone(),
+                salt: expected.salt,
+            });
+        }
+    }
+    Ok(id)
+}
+
+/// Looks up a game. Private games are only found by code, so their ids can't be guessed.
+fn find_game(games: &HashMap<u32, GameHost>, game: &GameRef) -> Option<u32> {
+    match game {
+        GameRef::Id(id) => games
+            .get(id)
+            .is_some_and(|host| !host.settings().private)
+            .then_some(*id),
+        GameRef::Code(code) => games
+            .iter()
+            .find(|(_, host)| host.settings().code.eq_ignore_ascii_case(code))
+            .map(|(id, _)| *id),
+    }
+}
+
+fn unused_join_code(games: &HashMap<u32, GameHost>) -> String {
+    loop {
+        let code = new_join_code();
+        if games.values().all(|game| game.settings().code != code) {
+            return code;
+        }
+    }
+}
+
+/// Hands clients to a running game, after any already waiting for it. Those it has no room for
+/// yet wait to be offered again, and if it turns out to be ending, they wait for it to end and
+/// join the fresh one started in its place. Waiting for the game here would hold up every other.
+fn join_game(
+    games: &HashMap<u32, GameHost>,
+    waiting_to_join: &mut HashMap<u32, VecDeque<Connection>>,
+    game_id: u32,
+    connections: Vec<Connection>,
+) {
+    let game = games.get(&game_id).expect("Joining a running game");
+    let waiting = waiting_to_join.entry(game_id).or_default();
+    waiting.extend(connections);
+    while let Some(connection) = waiting.pop_front() {
+        match game.new_client(connection) {
+            Ok(()) => {}
+            Err(TrySendError::Full(connection)) => {
+                waiting.push_front(connection);
+                return;
+            }
+            Err(TrySendError::Closed(connection)) => {
+                info!("Game {} is ending as clients join", game_id);
+                waiting.push_front(connection);
+                return;
+            }
+        }
+    }
+    waiting_to_join.remove(&game_id);
+}
+
+async fn process_hello(
+    read: &mut MessageReader,
+    write: &mut dyn MessageWriter,
+    passphrase_hash: Option<&PassphraseHash>,
+) -> anyhow::Result<Nonce> {
+    let client_message = read_handshake_message(read, "hello").await?;
+    let ToServerMessage::Hello {
+        protocol_version: client_version,
+        capabilities: client_capabilities,
+    } = client_message
+    else {
+        anyhow::bail!("Expected Hello from client - got: {:?}", client_message);
+    };
+    let Some(protocol_version) = SUPPORTED_PROTOCOL_VERSIONS.negotiate(client_version) else {
+        return reject(
+            write,
+            RejectReason::UnsupportedProtocolVersion {
+                requested: client_version,
+                supported: SUPPORTED_PROTOCOL_VERSIONS,
+            },
+        )
+        .await;
+    };
+    if let Some(passphrase_hash) = passphrase_hash {
+        if !client_capabilities
+            .iter()
+            .any(|c| c == CAPABILITY_PASSPHRASE_AUTH)
+        {
+            return reject(
+                write,
+                RejectReason::MissingCapability {
+                    capability: CAPABILITY_PASSPHRASE_AUTH.to_owned(),
+                },
+            )
+            .await;
+        }
+        authenticate(read, write, passphrase_hash).await?;
+    }
+    let capabilities = client_capabilities
+        .into_iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .collect();
+    let password_nonce = rand::random();
+    write_message(
+        write,
+        ToClientMessage::HelloAccepted {
+            protocol_version,
+            capabilities,
+            password_nonce,
+        },
+    )
+    .await?;
+    Ok(password_nonce)
+}
+
+/// Tells the client why it was refused, and fails the handshake with the same reason.
+async fn reject<T>(write: &mut dyn MessageWriter, reason: RejectReason) -> anyhow::Result<T> {
+    let error = anyhow::anyhow!("Rejected client: {reason}");
+    write_message(write, ToClientMessage::Rejected { reason }).await?;
+    Err(error)
+}
+
+async fn authenticate(
+    read: &mut MessageReader,
+    write: &mut dyn MessageWriter,
+    passphrase_hash: &PassphraseHash,
+) -> anyhow::Result<()> {
+    let nonce = rand::random();
+    write_message(write, ToClientMessage::AuthChallenge { nonce }).await?;
+    let client_message = read_handshake_message(read, "auth response").await?;
+    let ToServerMessage::AuthResponse { response } = client_message else {
+        anyhow::bail!(
+            "Expected AuthResponse from client - got: {:?}",
+            client_message
+        );
+    };
+    if !passphrase_hash.verify(&nonce, &response) {
+        return reject(write, RejectReason::WrongPassphrase).await;
+    }
+    Ok(())
+}
+
+async fn read_handshake_message(
+    read: &mut MessageReader,
+    what: &str,
+) -> anyhow::Result<ToServerMessage> {
     let result = tokio::time::timeout(Duration::from_millis(500), read.next())
         .await
-        .context("Timed out on receiving hello")?;
+        .with_context(|| format!("Timed out on receiving {what}"))?;
     let Some(result) = result else {
-        anyhow::bail!("Client closed connection before hello");
-    };
-    let client_message = result.context("Failed to read hello")?;
-    let ToServerMessage::Hello { game_id } = client_message else {
-        anyhow::bail!("Expected Hello from client - got: {:?}", client_message);
+        anyhow::bail!("Client closed connection before {what}");
     };
-    Ok(game_id)
+    result.with_context(|| format!("Failed to read {what}"))
 }
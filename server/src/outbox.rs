@@ -0,0 +1,172 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use nope_the_hoop_proto::message::ToClientMessage;
+use tokio::{sync::Notify, time::Instant};
+use tracing::info;
+
+use crate::transport::MessageWriter;
+
+/// How many messages can wait for a client before it's considered to be lagging.
+const OUTBOX_CAPACITY: usize = 256;
+/// How long a client can go without taking any messages before it's considered to be lagging, and
+/// how long a write can wait on it before the connection is given up on.
+const MAX_WRITE_STALL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutboxError {
+    /// Writing to the client failed, so it's gone.
+    Closed,
+    /// The client is too far behind on reading what it's sent.
+    Lagging,
+}
+
+impl std::fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::Closed => write!(f, "connection closed"),
+            OutboxError::Lagging => write!(f, "client is lagging too far behind"),
+        }
+    }
+}
+
+struct Queue {
+    messages: VecDeque<ToClientMessage>,
+    /// Set when the outbox is dropped: the writer sends what's left and stops.
+    draining: bool,
+    /// Set when the writer stops, for whatever reason.
+    writer_done: bool,
+    /// When the writer last took messages off the queue, or the queue last stopped being empty.
+    last_progress: Instant,
+}
+
+/// The messages waiting to be written to one client, and the task that writes them. Sending never
+/// waits on the network, so one slow client can't hold up the game.
+pub(crate) struct Outbox {
+    queue: Arc<Mutex<Queue>>,
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    pub(crate) fn new(write: Box<dyn MessageWriter>) -> Self {
+        let queue = Arc::new(Mutex::new(Queue {
+            messages: VecDeque::new(),
+            draining: false,
+            writer_done: false,
+            last_progress: Instant::now(),
+        }));
+        let notify = Arc::new(Notify::new());
+        let (writer_queue, writer_notify) = (queue.clone(), notify.clone());
+        tokio::spawn(async move {
+            if let Err(e) = write_loop(write, &writer_queue, &writer_notify).await {
+                info!("Client write error: {:#}", e);
+            }
+            writer_queue.lock().unwrap().writer_done = true;
+        });
+        Self { queue, notify }
+    }
+
+    /// Queues a message. When the queue is full, a tick or snapshot replaces the oldest queued one
+    /// instead of adding to the backlog.
+    pub(crate) fn send(&self, message: ToClientMessage) -> Result<(), OutboxError> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.writer_done {
+            return Err(OutboxError::Closed);
+        }
+        if !queue.messages.is_empty() && queue.last_progress.elapsed() > MAX_WRITE_STALL {
+            return Err(OutboxError::Lagging);
+        }
+        if queue.messages.len() >= OUTBOX_CAPACITY {
+            let stale = queue
+                .messages
+                .iter()
+                .position(|queued| supersedes(&message, queued))
+                .ok_or(OutboxError::Lagging)?;
+            let _stale = queue.messages.remove(stale);
+        }
+        if queue.messages.is_empty() {
+            queue.last_progress = Instant::now();
+        }
+        queue.messages.push_back(message);
+        self.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().draining = true;
+        self.notify.notify_one();
+    }
+}
+
+async fn write_loop(
+    mut write: Box<dyn MessageWriter>,
+    queue: &Mutex<Queue>,
+    notify: &Notify,
+) -> anyhow::Result<()> {
+    loop {
+        let (messages, draining) = {
+            let mut queue = queue.lock().unwrap();
+            queue.last_progress = Instant::now();
+            (queue.messages.drain(..).collect::<Vec<_>>(), queue.draining)
+        };
+        if messages.is_empty() {
+            if draining {
+                return Ok(());
+            }
+            notify.notified().await;
+            continue;
+        }
+        // Whatever piled up goes out together, with a single flush. A client that stopped reading
+        // would hold the write, and the connection with it, forever.
+        tokio::time::timeout(MAX_WRITE_STALL, write.write_messages(&messages))
+            .await
+            .map_err(|_| anyhow!("Client took nothing for {MAX_WRITE_STALL:?}"))??;
+    }
+}
+
+/// Whether `new` makes `old` pointless to send.
+fn supersedes(new: &ToClientMessage, old: &ToClientMessage) -> bool {
+    matches!(
+        (new, old),
+        // A dropped tick's moves are made up for by the next one's, and the next snapshot doesn't
+        // need the ones before it.
+        (ToClientMessage::Tick { .. }, ToClientMessage::Tick { .. })
+            | (
+                ToClientMessage::Snapshot { .. },
+                ToClientMessage::Snapshot { .. }
+            )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::transport::stream;
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_clients_that_stop_reading() {
+        // Room for a few bytes, so the writer is soon stuck on a client that doesn't read.
+        let (mut client, server) = tokio::io::duplex(64);
+        let (read, write) = stream(server);
+        let outbox = Outbox::new(write);
+        for _ in 0..10 {
+            let message = ToClientMessage::RoleSwapAnswered { accepted: true };
+            outbox.send(message).unwrap();
+        }
+        // As the host does when it lets a client go.
+        drop((read, outbox));
+
+        tokio::time::sleep(MAX_WRITE_STALL * 2).await;
+        // Writing only fails once the server's end of the connection is gone.
+        let closed = client.write_all(b"x").await;
+        assert!(closed.is_err(), "connection still open");
+    }
+}
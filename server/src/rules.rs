@@ -0,0 +1,318 @@
+use std::{collections::HashMap, time::Duration};
+
+use nope_the_hoop_proto::{
+    message::CommandError,
+    state::{GameState, MatchPhase, MatchWinner, Standing},
+};
+use tokio::time::Instant;
+
+/// How a match is played, the same for every game on a server.
+#[derive(Debug, Clone)]
+pub struct MatchRules {
+    pub rounds: u32,
+    pub shots_per_round: u32,
+    /// The longest a round can go on, if not every shot is taken first.
+    pub round_time: Duration,
+    /// The score that wins the match on the spot, if any.
+    pub points_to_win: Option<u32>,
+    /// How long the countdown before a round, and the pause after one, last.
+    pub countdown: Duration,
+    /// How long the results are up before the next match.
+    pub match_over_time: Duration,
+}
+
+/// What the match needs to know about the game to move on.
+pub(crate) struct MatchInputs<'a> {
+    /// Whether there's a hoop and at least one ball player to play with.
+    pub ready: bool,
+    pub state: &'a GameState,
+    pub balls_in_flight: bool,
+}
+
+pub(crate) struct Match {
+    rules: MatchRules,
+    phase: MatchPhase,
+    /// When the current phase runs out, for timed phases.
+    deadline: Option<Instant>,
+    /// Shots taken this round, by ball id.
+    shots_taken: HashMap<u32, u32>,
+}
+
+impl Match {
+    pub(crate) fn new(rules: MatchRules) -> Self {
+        Self {
+            rules,
+            phase: MatchPhase::WaitingForPlayers,
+            deadline: None,
+            shots_taken: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn rules(&self) -> &MatchRules {
+        &self.rules
+    }
+
+    pub(crate) fn phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(crate) fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// Checks that a ball can be shot now, without counting the shot.
+    pub(crate) fn check_shot(&self, id: u32) -> Result<(), CommandError> {
+        if !matches!(self.phase, MatchPhase::Playing { .. }) {
+            return Err(CommandError::NotPlaying);
+        }
+        if self.shots_left(id) == 0 {
+            return Err(CommandError::OutOfShots { id });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_shot(&mut self, id: u32) {
+        *self.shots_taken.entry(id).or_default() += 1;
+    }
+
+    fn shots_left(&self, id: u32) -> u32 {
+        let taken = self.shots_taken.get(&id).copied().unwrap_or(0);
+        self.rules.shots_per_round.saturating_sub(taken)
+    }
+
+    /// The phase the match should move on to, if it's done with the current one.
+    pub(crate) fn next_phase(&self, inputs: &MatchInputs, now: Instant) -> Option<MatchPhase> {
+        let timed_out = self.deadline.is_some_and(|deadline| deadline <= now);
+        match self.phase {
+            MatchPhase::WaitingForPlayers => {
+                inputs.ready.then_some(MatchPhase::Countdown { round: 1 })
+            }
+            MatchPhase::Countdown { .. } if !inputs.ready => Some(MatchPhase::WaitingForPlayers),
+            MatchPhase::Countdown { round } => timed_out.then_some(MatchPhase::Playing { round }),
+            MatchPhase::Playing { round } => {
+                let won = self.rules.points_to_win.is_some_and(|points| {
+                    inputs.state.scores.values().any(|&score| score >= points)
+                });
+                let all_shot = !inputs.balls_in_flight
+                    && inputs
+                        .state
+                        .ball_positions
+                        .keys()
+                        .all(|&id| self.shots_left(id) == 0);
+                if won || (round >= self.rules.rounds && (timed_out || all_shot)) {
+                    Some(MatchPhase::MatchOver)
+                } else if timed_out || all_shot {
+                    Some(MatchPhase::RoundOver { round })
+                } else {
+                    None
+                }
+            }
+            MatchPhase::RoundOver { round } => {
+                timed_out.then_some(MatchPhase::Countdown { round: round + 1 })
+            }
+            MatchPhase::MatchOver => timed_out.then_some(MatchPhase::WaitingForPlayers),
+        }
+    }
+
+    pub(crate) fn enter(&mut self, phase: MatchPhase, now: Instant) {
+        let duration = match phase {
+            MatchPhase::WaitingForPlayers => None,
+            MatchPhase::Countdown { .. } => {
+                self.shots_taken.clear();
+                Some(self.rules.countdown)
+            }
+            MatchPhase::Playing { .. } => Some(self.rules.round_time),
+            MatchPhase::RoundOver { .. } => Some(self.rules.countdown),
+            MatchPhase::MatchOver => Some(self.rules.match_over_time),
+        };
+        self.phase = phase;
+        self.deadline = duration.map(|duration| now + duration);
+    }
+}
+
+/// The players still in the game from best to worst, and who won. The hoop's player is only in
+/// the standings if they scored while they had a ball.
+pub(crate) fn standings(state: &GameState) -> (Vec<Standing>, MatchWinner) {
+    let hoop = (state.hoop_score > 0).then_some(Standing {
+        ball_id: None,
+        score: state.hoop_score,
+    });
+    let mut standings: Vec<_> = state
+        .ball_positions
+        .keys()
+        .map(|&ball_id| Standing {
+            ball_id: Some(ball_id),
+            score: state.scores.get(&ball_id).copied().unwrap_or(0),
+        })
+        .chain(hoop)
+        .collect();
+    standings.sort_by_key(|standing| (std::cmp::Reverse(standing.score), standing.ball_id));
+    let winner = match standings.as_slice() {
+        [] => MatchWinner::Hoop,
+        [first, ..] if first.score == 0 => MatchWinner::Hoop,
+        [first, second, ..] if first.score == second.score => MatchWinner::Draw,
+        [first, ..] => MatchWinner::Player {
+            ball_id: first.ball_id,
+        },
+    };
+    (standings, winner)
+}
+
+#[cfg(test)]
+mod tests {
+    use nope_the_hoop_proto::state::Point;
+
+    use super::*;
+
+    fn rules() -> MatchRules {
+        MatchRules {
+            rounds: 2,
+            shots_per_round: 1,
+            round_time: Duration::from_secs(30),
+            points_to_win: Some(3),
+            countdown: Duration::from_secs(3),
+            match_over_time: Duration::from_secs(10),
+        }
+    }
+
+    fn state(scores: &[(u32, u32)]) -> GameState {
+        GameState {
+            hoop_x: 0.,
+            ball_positions: scores
+                .iter()
+                .map(|&(id, _)| (id, Point::default()))
+                .collect(),
+            scores: scores.iter().copied().collect(),
+            hoop_score: 0,
+            nopes: 0,
+        }
+    }
+
+    /// Moves the match on as far as it goes at `now`.
+    fn advance(game_match: &mut Match, inputs: &MatchInputs, now: Instant) -> MatchPhase {
+        while let Some(phase) = game_match.next_phase(inputs, now) {
+            game_match.enter(phase, now);
+        }
+        game_match.phase()
+    }
+
+    #[test]
+    fn phases() {
+        let start = Instant::now();
+        let mut game_match = Match::new(rules());
+        let state = state(&[(0, 0)]);
+        let mut inputs = MatchInputs {
+            ready: false,
+            state: &state,
+            balls_in_flight: false,
+        };
+        assert_eq!(
+            advance(&mut game_match, &inputs, start),
+            MatchPhase::WaitingForPlayers
+        );
+        inputs.ready = true;
+        assert_eq!(
+            advance(&mut game_match, &inputs, start),
+            MatchPhase::Countdown { round: 1 }
+        );
+        let playing = start + Duration::from_secs(3);
+        assert_eq!(
+            advance(&mut game_match, &inputs, playing),
+            MatchPhase::Playing { round: 1 }
+        );
+        assert_eq!(game_match.check_shot(0), Ok(()));
+        game_match.record_shot(0);
+        assert_eq!(
+            game_match.check_shot(0),
+            Err(CommandError::OutOfShots { id: 0 })
+        );
+        // The round waits for the last shot to land.
+        inputs.balls_in_flight = true;
+        assert_eq!(
+            advance(&mut game_match, &inputs, playing),
+            MatchPhase::Playing { round: 1 }
+        );
+        inputs.balls_in_flight = false;
+        assert_eq!(
+            advance(&mut game_match, &inputs, playing),
+            MatchPhase::RoundOver { round: 1 }
+        );
+        assert_eq!(game_match.check_shot(0), Err(CommandError::NotPlaying));
+        assert_eq!(
+            advance(&mut game_match, &inputs, playing + Duration::from_secs(3)),
+            MatchPhase::Countdown { round: 2 }
+        );
+        let second_round = playing + Duration::from_secs(6);
+        assert_eq!(
+            advance(&mut game_match, &inputs, second_round),
+            MatchPhase::Playing { round: 2 }
+        );
+        assert_eq!(game_match.check_shot(0), Ok(()));
+        // The last round runs out of time.
+        let timed_out = second_round + Duration::from_secs(30);
+        assert_eq!(
+            advance(&mut game_match, &inputs, timed_out),
+            MatchPhase::MatchOver
+        );
+        // Then a new match starts.
+        assert_eq!(
+            advance(
+                &mut game_match,
+                &inputs,
+                timed_out + Duration::from_secs(10)
+            ),
+            MatchPhase::Countdown { round: 1 }
+        );
+    }
+
+    #[test]
+    fn points_to_win() {
+        let start = Instant::now();
+        let mut game_match = Match::new(rules());
+        game_match.enter(MatchPhase::Playing { round: 1 }, start);
+        let state = state(&[(0, 2), (1, 3)]);
+        let inputs = MatchInputs {
+            ready: true,
+            state: &state,
+            balls_in_flight: true,
+        };
+        assert_eq!(
+            game_match.next_phase(&inputs, start),
+            Some(MatchPhase::MatchOver)
+        );
+    }
+
+    #[test]
+    fn winners() {
+        let (standings, winner) = standings(&state(&[(0, 2), (1, 5), (2, 0)]));
+        assert_eq!(
+            standings.iter().map(|s| s.ball_id).collect::<Vec<_>>(),
+            [Some(1), Some(0), Some(2)]
+        );
+        assert_eq!(winner, MatchWinner::Player { ball_id: Some(1) });
+        assert_eq!(standings_winner(&[(0, 2), (1, 2)]), MatchWinner::Draw);
+        assert_eq!(standings_winner(&[(0, 0), (1, 0)]), MatchWinner::Hoop);
+        assert_eq!(standings_winner(&[]), MatchWinner::Hoop);
+    }
+
+    #[test]
+    fn hoop_keeps_its_score() {
+        let mut state = state(&[(0, 1), (1, 0)]);
+        state.hoop_score = 2;
+        let (standings, winner) = standings(&state);
+        assert_eq!(
+            standings.iter().map(|s| s.ball_id).collect::<Vec<_>>(),
+            [None, Some(0), Some(1)]
+        );
+        assert_eq!(winner, MatchWinner::Player { ball_id: None });
+    }
+
+    fn standings_winner(scores: &[(u32, u32)]) -> MatchWinner {
+        standings(&state(scores)).1
+    }
+}
@@ -0,0 +1,134 @@
+//! What carries messages between the server and its clients, so that everything past accepting a
+//! connection works the same over any of them.
+
+use std::pin::Pin;
+
+use anyhow::{anyhow, Context};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, SplitSink},
+    SinkExt, Stream, StreamExt,
+};
+use nope_the_hoop_proto::{
+    message::{ToClientMessage, ToServerMessage},
+    stream::MessageCodec,
+    udp_stream::{UdpReader, UdpWriter},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+use tokio_util::codec::Framed;
+
+/// The same limit as length-prefixed messages have, which a client's messages are well under.
+const MAX_WEBSOCKET_MESSAGE_SIZE: usize = 4096;
+
+/// The messages from a client. `Sync` as well as `Send`, because a lobby request that fails to
+/// send hands its connection back in an error, and errors have to be `Sync` to become
+/// [`anyhow::Error`]s.
+pub(crate) type MessageReader =
+    Pin<Box<dyn Stream<Item = anyhow::Result<ToServerMessage>> + Send + Sync>>;
+
+/// Sends messages to a client.
+pub(crate) trait MessageWriter: Send + Sync {
+    /// Sends `messages` in order, together if the transport allows.
+    fn write_messages<'a>(
+        &'a mut self,
+        messages: &'a [ToClientMessage],
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+type ServerCodec = MessageCodec<ToClientMessage, ToServerMessage>;
+
+impl<S: AsyncRead + AsyncWrite + Send + Sync> MessageWriter
+    for SplitSink<Framed<S, ServerCodec>, ToClientMessage>
+{
+    fn write_messages<'a>(
+        &'a mut self,
+        messages: &'a [ToClientMessage],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        let mut messages = stream::iter(messages.iter().cloned().map(Ok));
+        Box::pin(async move { self.send_all(&mut messages).await })
+    }
+}
+
+impl MessageWriter for UdpWriter<ToClientMessage> {
+    fn write_messages<'a>(
+        &'a mut self,
+        messages: &'a [ToClientMessage],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(UdpWriter::write_messages(self, messages.to_vec()))
+    }
+}
+
+impl MessageWriter for SplitSink<WebSocketStream<TcpStream>, Message> {
+    fn write_messages<'a>(
+        &'a mut self,
+        messages: &'a [ToClientMessage],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            for message in messages {
+                let mut buf = vec![];
+                ciborium::into_writer(message, &mut buf).context("Failed to serialize message")?;
+                self.feed(Message::Binary(buf))
+                    .await
+                    .context("Failed to write message")?;
+            }
+            self.flush().await.context("Failed to flush WebSocket")
+        })
+    }
+}
+
+pub(crate) async fn write_message(
+    write: &mut dyn MessageWriter,
+    message: ToClientMessage,
+) -> anyhow::Result<()> {
+    write.write_messages(&[message]).await
+}
+
+/// Length-prefixed messages over a TCP connection, or anything else that's a stream of bytes.
+pub(crate) fn stream(
+    stream: impl AsyncRead + AsyncWrite + Send + Sync + 'static,
+) -> (MessageReader, Box<dyn MessageWriter>) {
+    let (write, read) = Framed::new(stream, ServerCodec::new()).split();
+    (Box::pin(read), Box::new(write))
+}
+
+pub(crate) fn udp(
+    read: UdpReader<ToServerMessage>,
+    write: UdpWriter<ToClientMessage>,
+) -> (MessageReader, Box<dyn MessageWriter>) {
+    (Box::pin(read), Box::new(write))
+}
+
+/// Finishes the WebSocket handshake on a new connection, after which each binary message is a
+/// message to or from the client.
+pub(crate) async fn websocket(
+    stream: TcpStream,
+) -> anyhow::Result<(MessageReader, Box<dyn MessageWriter>)> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_WEBSOCKET_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_WEBSOCKET_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let websocket = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+        .await
+        .context("WebSocket handshake failed")?;
+    let (write, read) = websocket.split();
+    // Pings are answered by the WebSocket itself, and a close ends the stream after it.
+    let read = read.filter_map(|message| {
+        future::ready(match message {
+            Ok(Message::Binary(buf)) => {
+                Some(ciborium::from_reader(&buf[..]).context("Failed to deserialize message"))
+            }
+            Ok(Message::Text(_)) => Some(Err(anyhow!("Expected a binary message - got text"))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e).context("Failed to read WebSocket")),
+        })
+    });
+    Ok((Box::pin(read), Box::new(write)))
+}
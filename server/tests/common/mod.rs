@@ -0,0 +1,179 @@
+//! Helpers for running a server and talking to it as a client. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::{process::Stdio, time::Duration};
+
+use futures::{Stream, StreamExt};
+use nope_the_hoop_proto::{
+    auth::{GamePassword, SessionToken},
+    lobby::{GameInfo, GameRef},
+    message::{PreferredRole, ToClientMessage, ToServerMessage, CAPABILITIES, PROTOCOL_VERSION},
+    state::UpdateState,
+    stream::{write_message, MessageStream},
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, Lines},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    process::{Child, ChildStdout, Command},
+};
+
+pub type ClientMessageStream = MessageStream<tokio::net::tcp::OwnedReadHalf, ToClientMessage>;
+
+/// Starts the server on a free port, returning it with the port.
+pub async fn start_server(args: &[&str]) -> (Child, u16) {
+    let (server, mut lines) = spawn_server(args);
+    let port = port_after(&mut lines, "Listening on ").await;
+    drain(lines);
+    (server, port)
+}
+
+/// Starts the server taking UDP connections too, returning it with its TCP and UDP ports.
+pub async fn start_udp_server(args: &[&str]) -> (Child, u16, u16) {
+    start_server_also(args, "--udp-port", "Listening for UDP on ").await
+}
+
+/// Starts the server taking WebSocket connections too, returning it with both its ports.
+pub async fn start_ws_server(args: &[&str]) -> (Child, u16, u16) {
+    start_server_also(args, "--ws-port", "Listening for WebSocket on ").await
+}
+
+/// Starts the server with a second listener on the free port given to `port_arg`.
+async fn start_server_also(args: &[&str], port_arg: &str, listening: &str) -> (Child, u16, u16) {
+    let (server, mut lines) = spawn_server(&[&[port_arg, "0"], args].concat());
+    let port = port_after(&mut lines, "Listening on ").await;
+    let other_port = port_after(&mut lines, listening).await;
+    drain(lines);
+    (server, port, other_port)
+}
+
+type OutputLines = Lines<BufReader<ChildStdout>>;
+
+fn spawn_server(args: &[&str]) -> (Child, OutputLines) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_nope-the-hoop-server"))
+        .args(["--port", "0"])
+        .args(args)
+        .env("NO_COLOR", "1")
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Starting server");
+    let lines = BufReader::new(server.stdout.take().unwrap()).lines();
+    (server, lines)
+}
+
+/// Reads the server's output up to the address after `prefix`, returning its port.
+async fn port_after(lines: &mut OutputLines, prefix: &str) -> u16 {
+    while let Some(line) = lines.next_line().await.expect("Reading server output") {
+        let Some((_, address)) = line.split_once(prefix) else {
+            continue;
+        };
+        return address.trim().rsplit(':').next().unwrap().parse().unwrap();
+    }
+    panic!("Server exited before listening");
+}
+
+/// Keeps draining the output so the server never blocks on it.
+fn drain(mut lines: OutputLines) {
+    tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+}
+
+/// Connects and says hello, leaving the client in the lobby.
+pub async fn connect(port: u16) -> (ClientMessageStream, OwnedWriteHalf) {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let hello = ToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    write_message(&mut write, &hello).await.unwrap();
+    (MessageStream::new(read), write)
+}
+
+pub async fn create_game(port: u16, name: &str) -> GameInfo {
+    create_game_with(port, name, false, None).await
+}
+
+pub async fn create_game_with(
+    port: u16,
+    name: &str,
+    private: bool,
+    password: Option<&str>,
+) -> GameInfo {
+    let (mut read, mut write) = connect(port).await;
+    let create = ToServerMessage::CreateGame {
+        name: name.to_owned(),
+        private,
+        password: password.map(|password| GamePassword::new(password, rand::random())),
+    };
+    write_message(&mut write, &create).await.unwrap();
+    let ToClientMessage::GameCreated { game } = expect_message(&mut read, |m| {
+        matches!(m, ToClientMessage::GameCreated { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    game
+}
+
+pub async fn join(
+    port: u16,
+    game_id: u32,
+    session: Option<SessionToken>,
+) -> (ClientMessageStream, OwnedWriteHalf) {
+    join_as(port, game_id, session, None).await
+}
+
+pub async fn join_as(
+    port: u16,
+    game_id: u32,
+    session: Option<SessionToken>,
+    preferred_role: Option<PreferredRole>,
+) -> (ClientMessageStream, OwnedWriteHalf) {
+    let (read, mut write) = connect(port).await;
+    let join = ToServerMessage::JoinGame {
+        game: GameRef::Id(game_id),
+        session,
+        password: None,
+        preferred_role,
+    };
+    write_message(&mut write, &join).await.unwrap();
+    (read, write)
+}
+
+/// Reads messages until one matches, failing if none does in time.
+pub async fn expect_message(
+    read: &mut (impl Stream<Item = anyhow::Result<ToClientMessage>> + Unpin),
+    matches: impl Fn(&ToClientMessage) -> bool,
+) -> ToClientMessage {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let message = read.next().await.expect("Connection open").expect("Read");
+            if matches(&message) {
+                return message;
+            }
+        }
+    })
+    .await
+    .expect("Timed out waiting for message")
+}
+
+/// Reads ticks and events until one has a matching update, failing if none does in time.
+pub async fn expect_update(
+    read: &mut (impl Stream<Item = anyhow::Result<ToClientMessage>> + Unpin),
+    matches: impl Fn(&UpdateState) -> bool,
+) -> UpdateState {
+    let (ToClientMessage::Tick { updates, .. } | ToClientMessage::Events { updates, .. }) =
+        expect_message(read, |m| {
+            matches!(
+                m,
+                ToClientMessage::Tick { updates, .. } | ToClientMessage::Events { updates, .. }
+                    if updates.iter().any(&matches)
+            )
+        })
+        .await
+    else {
+        unreachable!()
+    };
+    updates.into_iter().find(matches).unwrap()
+}
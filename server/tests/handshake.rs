@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use common::{connect, expect_message, start_server, ClientMessageStream};
+use futures::StreamExt;
+use nope_the_hoop_proto::{
+    auth::PassphraseHash,
+    message::{
+        RejectReason, ToClientMessage, ToServerMessage, CAPABILITIES, MIN_PROTOCOL_VERSION,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    stream::{write_message, MessageStream},
+};
+use tokio::net::TcpStream;
+
+mod common;
+
+/// Waits for the server to hang up after refusing the client.
+async fn expect_closed(read: &mut ClientMessageStream) {
+    let next = tokio::time::timeout(Duration::from_secs(5), read.next())
+        .await
+        .expect("Timed out waiting for the server to close the connection");
+    assert!(
+        next.is_none(),
+        "Expected the connection to close, got {next:?}"
+    );
+}
+
+#[tokio::test]
+async fn wrong_passphrase_is_rejected() {
+    let (_server, port) = start_server(&["--passphrase", "open sesame"]).await;
+    let (mut read, mut write) = connect(port).await;
+    let ToClientMessage::AuthChallenge { nonce } = expect_message(&mut read, |m| {
+        matches!(m, ToClientMessage::AuthChallenge { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    let response = PassphraseHash::new("open says me").respond(&nonce);
+    write_message(&mut write, &ToServerMessage::AuthResponse { response })
+        .await
+        .unwrap();
+    let rejected =
+        expect_message(&mut read, |m| matches!(m, ToClientMessage::Rejected { .. })).await;
+    assert_eq!(
+        rejected,
+        ToClientMessage::Rejected {
+            reason: RejectReason::WrongPassphrase
+        }
+    );
+    expect_closed(&mut read).await;
+}
+
+#[tokio::test]
+async fn unsupported_protocol_version_is_rejected() {
+    let (_server, port) = start_server(&[]).await;
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let mut read: ClientMessageStream = MessageStream::new(read);
+    let requested = MIN_PROTOCOL_VERSION - 1;
+    let hello = ToServerMessage::Hello {
+        protocol_version: requested,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    write_message(&mut write, &hello).await.unwrap();
+    let rejected =
+        expect_message(&mut read, |m| matches!(m, ToClientMessage::Rejected { .. })).await;
+    assert_eq!(
+        rejected,
+        ToClientMessage::Rejected {
+            reason: RejectReason::UnsupportedProtocolVersion {
+                requested,
+                supported: SUPPORTED_PROTOCOL_VERSIONS,
+            }
+        }
+    );
+    expect_closed(&mut read).await;
+}
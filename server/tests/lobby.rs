@@ -0,0 +1,146 @@
+use common::{
+    connect, create_game, create_game_with, expect_message, start_server, ClientMessageStream,
+};
+use nope_the_hoop_proto::{
+    auth::PassphraseHash,
+    lobby::{GameRef, LobbyError},
+    message::{ToClientMessage, ToServerMessage},
+    stream::write_message,
+};
+use tokio::net::tcp::OwnedWriteHalf;
+
+mod common;
+
+#[tokio::test]
+async fn lobby_lists_and_joins_by_code() {
+    let (_server, port) = start_server(&[]).await;
+    let created = create_game(port, "  Friday hoops ").await;
+    assert_eq!(created.name, "Friday hoops");
+    let (mut lobby, mut lobby_write) = connect(port).await;
+    write_message(&mut lobby_write, &ToServerMessage::ListGames)
+        .await
+        .unwrap();
+    let listed = expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::GameList { .. })
+    })
+    .await;
+    assert_eq!(
+        listed,
+        ToClientMessage::GameList {
+            games: vec![created.clone()]
+        }
+    );
+
+    // A failed join leaves the client in the lobby to try again.
+    let missing = GameRef::Code("NOPE00".to_owned());
+    let join = ToServerMessage::JoinGame {
+        game: missing.clone(),
+        session: None,
+        password: None,
+        preferred_role: None,
+    };
+    write_message(&mut lobby_write, &join).await.unwrap();
+    let error = expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::LobbyError { .. })
+    })
+    .await;
+    assert_eq!(
+        error,
+        ToClientMessage::LobbyError {
+            error: LobbyError::NoSuchGame { game: missing }
+        }
+    );
+    let join = ToServerMessage::JoinGame {
+        game: GameRef::Code(created.code.to_lowercase()),
+        session: None,
+        password: None,
+        preferred_role: None,
+    };
+    write_message(&mut lobby_write, &join).await.unwrap();
+    expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn private_game_needs_code_and_password() {
+    let (_server, port) = start_server(&[]).await;
+    let created = create_game_with(port, "practice", true, Some("swish")).await;
+    assert!(created.private && created.password_required);
+    let (mut lobby, mut lobby_write) = connect(port).await;
+    let ToClientMessage::HelloAccepted { password_nonce, .. } = expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::HelloAccepted { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    write_message(&mut lobby_write, &ToServerMessage::ListGames)
+        .await
+        .unwrap();
+    let listed = expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::GameList { .. })
+    })
+    .await;
+    assert_eq!(listed, ToClientMessage::GameList { games: vec![] });
+
+    let by_id = GameRef::Id(created.id);
+    let by_code = GameRef::Code(created.code.clone());
+    let join = |game, password: Option<PassphraseHash>| ToServerMessage::JoinGame {
+        game,
+        session: None,
+        password: password.map(|p| p.respond(&password_nonce)),
+        preferred_role: None,
+    };
+    let error = refused_join(&mut lobby, &mut lobby_write, join(by_id.clone(), None)).await;
+    assert_eq!(error, LobbyError::NoSuchGame { game: by_id });
+    // Clients that join by code learn the salt from the first refusal.
+    let LobbyError::WrongGamePassword { game, salt } =
+        refused_join(&mut lobby, &mut lobby_write, join(by_code.clone(), None)).await
+    else {
+        panic!("Expected a wrong password error");
+    };
+    assert_eq!(game, by_code);
+    let wrong_passwords = [
+        PassphraseHash::salted("brick", &salt),
+        // Unsalted, as the hash of the password alone would open every game that has it.
+        PassphraseHash::new("swish"),
+    ];
+    for password in wrong_passwords {
+        let error = refused_join(
+            &mut lobby,
+            &mut lobby_write,
+            join(by_code.clone(), Some(password)),
+        )
+        .await;
+        assert_eq!(
+            error,
+            LobbyError::WrongGamePassword {
+                game: by_code.clone(),
+                salt
+            }
+        );
+    }
+    let join = join(by_code, Some(PassphraseHash::salted("swish", &salt)));
+    write_message(&mut lobby_write, &join).await.unwrap();
+    expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+}
+
+/// Asks to join a game, returning why the lobby refused.
+async fn refused_join(
+    read: &mut ClientMessageStream,
+    write: &mut OwnedWriteHalf,
+    join: ToServerMessage,
+) -> LobbyError {
+    write_message(write, &join).await.unwrap();
+    let ToClientMessage::LobbyError { error } =
+        expect_message(read, |m| matches!(m, ToClientMessage::LobbyError { .. })).await
+    else {
+        unreachable!()
+    };
+    error
+}
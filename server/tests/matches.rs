@@ -0,0 +1,94 @@
+use common::{create_game, expect_message, join, start_server};
+use nope_the_hoop_proto::{
+    message::{CommandError, ToClientMessage, ToServerMessage},
+    state::{MatchPhase, MatchWinner, Standing},
+    stream::write_message,
+};
+
+mod common;
+
+#[tokio::test]
+async fn match_runs_to_the_end() {
+    let (_server, port) = start_server(&[
+        "--rounds",
+        "1",
+        "--shots-per-round",
+        "1",
+        "--countdown-secs",
+        "0",
+    ])
+    .await;
+    let game = create_game(port, "match").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    let waiting = expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::PhaseChanged { .. })
+    })
+    .await;
+    assert!(matches!(
+        waiting,
+        ToClientMessage::PhaseChanged {
+            phase: MatchPhase::WaitingForPlayers,
+            rounds: 1,
+            remaining_secs: None,
+        }
+    ));
+    let (mut ball, mut ball_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    expect_message(&mut ball, |m| {
+        matches!(
+            m,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { round: 1 },
+                ..
+            }
+        )
+    })
+    .await;
+
+    // One shot straight down, which resets the ball once it falls off the court.
+    let shot = ToServerMessage::ShootBall {
+        id,
+        angle: -std::f32::consts::FRAC_PI_2,
+        seconds_pressed: 1.,
+    };
+    write_message(&mut ball_write, &shot).await.unwrap();
+    write_message(&mut ball_write, &shot).await.unwrap();
+    let rejected = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::CommandRejected { .. })
+    })
+    .await;
+    assert!(matches!(
+        rejected,
+        ToClientMessage::CommandRejected {
+            error: CommandError::OutOfShots { .. } | CommandError::BallInFlight { .. }
+        }
+    ));
+    let over = expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::MatchOver { .. })
+    })
+    .await;
+    assert_eq!(
+        over,
+        ToClientMessage::MatchOver {
+            standings: vec![Standing {
+                ball_id: Some(id),
+                score: 0
+            }],
+            hoop_nopes: 0,
+            winner: MatchWinner::Hoop,
+        }
+    );
+    write_message(&mut ball_write, &shot).await.unwrap();
+    expect_message(&mut ball, |m| {
+        *m == ToClientMessage::CommandRejected {
+            error: CommandError::NotPlaying,
+        }
+    })
+    .await;
+}
@@ -0,0 +1,228 @@
+use std::time::Duration;
+
+use common::{connect, create_game, expect_message, expect_update, join, start_server};
+use nope_the_hoop_proto::{
+    lobby::{GameRef, LobbyError},
+    message::{ToClientMessage, ToServerMessage},
+    state::{MatchPhase, UpdateState},
+    stream::write_message,
+};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+mod common;
+
+#[tokio::test]
+async fn garbage_connection_does_not_affect_games() {
+    let (mut server, port) = start_server(&[]).await;
+    let game = create_game(port, "garbage").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+
+    // One client that never says anything, and one that sends garbage.
+    let _silent = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut garbage = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    garbage.write_all(&[0xff; 64]).await.unwrap();
+    drop(garbage);
+
+    let (mut ball, _ball_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    expect_update(
+        &mut hoop,
+        |u| matches!(u, UpdateState::AddBall { id: added, .. } if *added == id),
+    )
+    .await;
+    assert!(server.try_wait().unwrap().is_none(), "Server exited");
+}
+
+#[tokio::test]
+async fn idle_game_ends() {
+    let (_server, port) = start_server(&["--idle-timeout-secs", "1"]).await;
+    for _ in 0..2 {
+        let game = create_game(port, "idle").await;
+        let (mut hoop, hoop_write) = join(port, game.id, None).await;
+        expect_message(&mut hoop, |m| {
+            matches!(m, ToClientMessage::EstablishAsHoop { .. })
+        })
+        .await;
+        let (mut ball, ball_write) = join(port, game.id, None).await;
+        // A fresh game starts its ball ids over.
+        expect_message(&mut ball, |m| {
+            matches!(m, ToClientMessage::EstablishAsBall { id: 0, .. })
+        })
+        .await;
+        drop((hoop, hoop_write, ball, ball_write));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let (mut lobby, _lobby_write) = join(port, game.id, None).await;
+        expect_message(&mut lobby, |m| {
+            matches!(
+                m,
+                ToClientMessage::LobbyError {
+                    error: LobbyError::NoSuchGame { .. }
+                }
+            )
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn clients_joining_at_once_all_get_in() {
+    let (_server, port) = start_server(&[]).await;
+    let game = create_game(port, "crowd").await;
+    // More than the game takes in one go, all asking together.
+    let mut clients = vec![];
+    for _ in 0..16 {
+        clients.push(connect(port).await);
+    }
+    for (_, write) in &mut clients {
+        let join = ToServerMessage::JoinGame {
+            game: GameRef::Id(game.id),
+            session: None,
+            password: None,
+            preferred_role: None,
+        };
+        write_message(write, &join).await.unwrap();
+    }
+    for (read, _) in &mut clients {
+        expect_message(read, |m| {
+            matches!(
+                m,
+                ToClientMessage::EstablishAsHoop { .. }
+                    | ToClientMessage::EstablishAsBall { .. }
+                    | ToClientMessage::EstablishAsObserver { .. }
+            )
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn games_are_capped() {
+    let (_server, port) = start_server(&["--max-games", "1", "--idle-timeout-secs", "1"]).await;
+    create_game(port, "first").await;
+    let (mut lobby, mut lobby_write) = connect(port).await;
+    let create = ToServerMessage::CreateGame {
+        name: "second".to_owned(),
+        private: false,
+        password: None,
+    };
+    write_message(&mut lobby_write, &create).await.unwrap();
+    let refused = expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::LobbyError { .. })
+    })
+    .await;
+    assert_eq!(
+        refused,
+        ToClientMessage::LobbyError {
+            error: LobbyError::TooManyGames
+        }
+    );
+
+    // Once the first is over there's room again.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    write_message(&mut lobby_write, &create).await.unwrap();
+    expect_message(&mut lobby, |m| {
+        matches!(m, ToClientMessage::GameCreated { .. })
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn reconnect_keeps_role() {
+    let (_server, port) = start_server(&[]).await;
+    let game = create_game(port, "reconnect").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+    let (mut ball, ball_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id, session } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    drop((ball, ball_write));
+
+    let (mut ball, _ball_write) = join(port, game.id, Some(session)).await;
+    let established = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await;
+    assert_eq!(
+        established,
+        ToClientMessage::EstablishAsBall { id, session }
+    );
+    // A newcomer gets a new ball rather than the reclaimed one.
+    let (mut other_ball, _other_ball_write) = join(port, game.id, None).await;
+    let established = expect_message(&mut other_ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await;
+    assert!(
+        matches!(established, ToClientMessage::EstablishAsBall { id: other, .. } if other != id)
+    );
+}
+
+#[tokio::test]
+async fn reconnect_mid_match() {
+    let (_server, port) = start_server(&[
+        "--rounds",
+        "2",
+        "--round-secs",
+        "1",
+        "--countdown-secs",
+        "2",
+    ])
+    .await;
+    let game = create_game(port, "mid-match").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+    let (mut ball, ball_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { session, .. } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    let phase_changed = |phase| move |m: &ToClientMessage| matches!(m, ToClientMessage::PhaseChanged { phase: p, .. } if *p == phase);
+    expect_message(&mut hoop, phase_changed(MatchPhase::Playing { round: 1 })).await;
+    expect_message(&mut hoop, phase_changed(MatchPhase::Countdown { round: 2 })).await;
+
+    // The ball drops during the countdown and comes back, and the match goes on where it was.
+    drop((ball, ball_write));
+    let (mut ball, _ball_write) = join(port, game.id, Some(session)).await;
+    expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await;
+    let next = expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::PhaseChanged { .. })
+    })
+    .await;
+    assert!(
+        matches!(
+            next,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { round: 2 },
+                ..
+            }
+        ),
+        "got {next:?}"
+    );
+}
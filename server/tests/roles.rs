@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use common::{
+    create_game, expect_message, expect_update, join, join_as, start_server, ClientMessageStream,
+};
+use nope_the_hoop_proto::{
+    message::{CommandError, HorizontalDirection, PreferredRole, ToClientMessage, ToServerMessage},
+    state::{MatchPhase, MatchWinner, Standing, UpdateState},
+    stream::write_message,
+};
+use nope_the_hoop_sim::{moved_hoop_x, INITIAL_HOOP_X};
+
+mod common;
+
+async fn established(read: &mut ClientMessageStream) -> ToClientMessage {
+    expect_message(read, |m| {
+        matches!(
+            m,
+            ToClientMessage::EstablishAsHoop { .. }
+                | ToClientMessage::EstablishAsBall { .. }
+                | ToClientMessage::EstablishAsObserver { .. }
+        )
+    })
+    .await
+}
+
+#[tokio::test]
+async fn preferred_roles() {
+    let (_server, port) = start_server(&["--max-ball-players", "1"]).await;
+    let game = create_game(port, "preferences").await;
+    let (mut ball, _ball_write) = join_as(port, game.id, None, Some(PreferredRole::Ball)).await;
+    assert!(matches!(
+        established(&mut ball).await,
+        ToClientMessage::EstablishAsBall { .. }
+    ));
+    let (mut observer, _observer_write) =
+        join_as(port, game.id, None, Some(PreferredRole::Observer)).await;
+    assert!(matches!(
+        established(&mut observer).await,
+        ToClientMessage::EstablishAsObserver { .. }
+    ));
+    // With the only ball slot taken, a client that wants a ball gets the free hoop instead.
+    let (mut hoop, _hoop_write) = join_as(port, game.id, None, Some(PreferredRole::Ball)).await;
+    assert!(matches!(
+        established(&mut hoop).await,
+        ToClientMessage::EstablishAsHoop { .. }
+    ));
+}
+
+#[tokio::test]
+async fn players_only_command_their_own_role() {
+    let (_server, port) = start_server(&[]).await;
+    let game = create_game(port, "permissions").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    established(&mut hoop).await;
+    let (mut first, _first_write) = join_as(port, game.id, None, Some(PreferredRole::Ball)).await;
+    let ToClientMessage::EstablishAsBall { id: first_id, .. } = established(&mut first).await
+    else {
+        panic!("Second client should be a ball");
+    };
+    let (mut second, mut second_write) =
+        join_as(port, game.id, None, Some(PreferredRole::Ball)).await;
+    established(&mut second).await;
+
+    let move_hoop = ToServerMessage::MoveHoop {
+        direction: HorizontalDirection::Left,
+        seconds_pressed: 0.01,
+        sequence: 1,
+    };
+    write_message(&mut second_write, &move_hoop).await.unwrap();
+    expect_message(&mut second, |m| {
+        *m == ToClientMessage::CommandRejected {
+            error: CommandError::NotTheHoop,
+        }
+    })
+    .await;
+
+    let shot = ToServerMessage::ShootBall {
+        id: first_id,
+        angle: std::f32::consts::FRAC_PI_4,
+        seconds_pressed: 0.1,
+    };
+    write_message(&mut second_write, &shot).await.unwrap();
+    expect_message(&mut second, |m| {
+        *m == ToClientMessage::CommandRejected {
+            error: CommandError::NotYourBall { id: first_id },
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn hoop_moves_come_back_with_their_sequence() {
+    let (_server, port) = start_server(&[]).await;
+    let game = create_game(port, "prediction").await;
+    let (mut hoop, mut hoop_write) = join(port, game.id, None).await;
+    established(&mut hoop).await;
+    let mut predicted = INITIAL_HOOP_X;
+    for sequence in 1..=3 {
+        let direction = HorizontalDirection::Right;
+        let seconds_pressed = 0.01;
+        let message = ToServerMessage::MoveHoop {
+            direction,
+            seconds_pressed,
+            sequence,
+        };
+        write_message(&mut hoop_write, &message).await.unwrap();
+        predicted = moved_hoop_x(predicted, direction, seconds_pressed);
+    }
+    // The moves can come back together, but the last one has to be in there.
+    let UpdateState::MoveHoop { x, .. } = expect_update(&mut hoop, |u| {
+        matches!(u, UpdateState::MoveHoop { sequence: 3, .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    assert_eq!(x, predicted);
+}
+
+#[tokio::test]
+async fn swap_hoop_between_rounds() {
+    let (_server, port) = start_server(&["--round-secs", "1", "--countdown-secs", "0"]).await;
+    let game = create_game(port, "swap").await;
+    let (mut hoop, mut hoop_write) = join(port, game.id, None).await;
+    established(&mut hoop).await;
+    let (mut ball, mut ball_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = established(&mut ball).await else {
+        panic!("Second client should be a ball");
+    };
+
+    write_message(&mut ball_write, &ToServerMessage::RequestRoleSwap)
+        .await
+        .unwrap();
+    let request = expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::RoleSwapRequested { .. })
+    })
+    .await;
+    assert_eq!(request, ToClientMessage::RoleSwapRequested { ball_id: id });
+    let answer = ToServerMessage::AnswerRoleSwap {
+        ball_id: id,
+        accept: true,
+    };
+    write_message(&mut hoop_write, &answer).await.unwrap();
+    let answered = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::RoleSwapAnswered { .. })
+    })
+    .await;
+    assert_eq!(
+        answered,
+        ToClientMessage::RoleSwapAnswered { accepted: true }
+    );
+
+    assert!(matches!(
+        established(&mut ball).await,
+        ToClientMessage::EstablishAsHoop { .. }
+    ));
+    assert!(matches!(
+        established(&mut hoop).await,
+        ToClientMessage::EstablishAsBall { id: taken, .. } if taken == id
+    ));
+    expect_message(&mut hoop, |m| {
+        *m == ToClientMessage::RolesSwapped { ball_id: id }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn hoop_rotates() {
+    let (_server, port) = start_server(&[
+        "--round-secs",
+        "1",
+        "--countdown-secs",
+        "0",
+        "--rotate-hoop",
+    ])
+    .await;
+    let game = create_game(port, "rotation").await;
+    let (mut first, _first_write) = join(port, game.id, None).await;
+    established(&mut first).await;
+    let (mut second, _second_write) = join(port, game.id, None).await;
+    established(&mut second).await;
+    let (mut third, _third_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id: third_ball, .. } = established(&mut third).await
+    else {
+        panic!("Third client should be a ball");
+    };
+
+    // The hoop goes to the second client, then the third, in join order.
+    assert!(matches!(
+        established(&mut second).await,
+        ToClientMessage::EstablishAsHoop { .. }
+    ));
+    let started = tokio::time::Instant::now();
+    assert!(matches!(
+        established(&mut third).await,
+        ToClientMessage::EstablishAsHoop { .. }
+    ));
+    assert!(started.elapsed() > Duration::from_millis(500));
+    assert!(matches!(
+        established(&mut second).await,
+        ToClientMessage::EstablishAsBall { id, .. } if id == third_ball
+    ));
+}
+
+#[tokio::test]
+async fn scores_follow_players() {
+    let (_server, port) = start_server(&[
+        "--countdown-secs",
+        "0",
+        "--points-to-win",
+        "1",
+        "--rotate-hoop",
+    ])
+    .await;
+    let game = create_game(port, "scores").await;
+    let (mut first, _first_write) = join(port, game.id, None).await;
+    established(&mut first).await;
+    let (mut second, mut second_write) = join(port, game.id, None).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = established(&mut second).await else {
+        panic!("Second client should be a ball");
+    };
+    expect_message(&mut second, |m| {
+        matches!(
+            m,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { round: 1 },
+                ..
+            }
+        )
+    })
+    .await;
+
+    // A lob into the hoop where it starts, which wins the match and rotates the hoop.
+    let shot = ToServerMessage::ShootBall {
+        id,
+        angle: std::f32::consts::FRAC_PI_4,
+        seconds_pressed: 0.4323,
+    };
+    write_message(&mut second_write, &shot).await.unwrap();
+    // The shot takes longer than a single wait allows, so this waits for it to fly halfway first.
+    expect_update(
+        &mut first,
+        |u| matches!(u, UpdateState::MoveBall { position, .. } if position.x > 0.),
+    )
+    .await;
+    let over = expect_message(&mut first, |m| {
+        matches!(m, ToClientMessage::MatchOver { .. })
+    })
+    .await;
+    let ToClientMessage::MatchOver {
+        standings, winner, ..
+    } = over
+    else {
+        unreachable!()
+    };
+    // The first client has the ball now, but the point stays with the second, who has the hoop.
+    assert_eq!(
+        standings,
+        [
+            Standing {
+                ball_id: None,
+                score: 1
+            },
+            Standing {
+                ball_id: Some(id),
+                score: 0
+            },
+        ]
+    );
+    assert_eq!(winner, MatchWinner::Player { ball_id: None });
+    assert!(matches!(
+        established(&mut second).await,
+        ToClientMessage::EstablishAsHoop { .. }
+    ));
+}
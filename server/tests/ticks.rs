@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use common::{create_game, expect_message, join, join_as, start_server, ClientMessageStream};
+use futures::StreamExt;
+use nope_the_hoop_proto::{
+    message::{PreferredRole, ToClientMessage, ToServerMessage},
+    state::{GameState, MatchPhase, UpdateState},
+    stream::write_message,
+};
+use serde::Serialize;
+use tokio::{net::tcp::OwnedWriteHalf, process::Child};
+
+mod common;
+
+struct TestGame {
+    _server: Child,
+    _hoop_write: OwnedWriteHalf,
+    /// The ball players' ids, and their connections to shoot with.
+    balls: Vec<(u32, OwnedWriteHalf)>,
+    observer: ClientMessageStream,
+    observer_write: OwnedWriteHalf,
+}
+
+/// Starts a game with a hoop, `balls` ball players and an observer, and waits for play to start.
+async fn start_game(balls: usize) -> TestGame {
+    let (_server, port) = start_server(&["--countdown-secs", "0"]).await;
+    let game = create_game(port, "ticks").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    // Whoever joins first gets the hoop, so the balls have to wait their turn.
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+    let mut ball_players = vec![];
+    for _ in 0..balls {
+        let (mut ball, ball_write) = join(port, game.id, None).await;
+        let ToClientMessage::EstablishAsBall { id, .. } = expect_message(&mut ball, |m| {
+            matches!(m, ToClientMessage::EstablishAsBall { .. })
+        })
+        .await
+        else {
+            unreachable!()
+        };
+        ball_players.push((id, ball_write));
+    }
+    let (mut observer, observer_write) =
+        join_as(port, game.id, None, Some(PreferredRole::Observer)).await;
+    expect_message(&mut observer, |m| {
+        matches!(
+            m,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { .. },
+                ..
+            }
+        )
+    })
+    .await;
+    TestGame {
+        _server,
+        _hoop_write,
+        balls: ball_players,
+        observer,
+        observer_write,
+    }
+}
+
+async fn shoot(id: u32, write: &mut OwnedWriteHalf) {
+    let shot = ToServerMessage::ShootBall {
+        id,
+        angle: 1.2,
+        seconds_pressed: 1.,
+    };
+    write_message(write, &shot).await.unwrap();
+}
+
+/// How updates went out before they were batched: each in its own message, after a message for
+/// the tick they happened in.
+#[derive(Serialize)]
+enum PerUpdateMessage {
+    UpdateState(UpdateState),
+    Tick { tick: u64 },
+}
+
+/// The bytes a message takes on the wire, length prefix included.
+fn wire_size(message: &impl Serialize) -> usize {
+    let mut buf = vec![];
+    ciborium::into_writer(message, &mut buf).unwrap();
+    buf.len() + 2
+}
+
+#[tokio::test]
+async fn ticks_count_up() {
+    let mut game = start_game(1).await;
+    let (id, ball_write) = &mut game.balls[0];
+    shoot(*id, ball_write).await;
+    let observer = &mut game.observer;
+    let mut last_tick = 0;
+    let mut moves = 0;
+    while moves < 10 {
+        if let ToClientMessage::Tick { tick, updates } = observer.next().await.unwrap().unwrap() {
+            assert!(tick > last_tick, "tick {tick} after {last_tick}");
+            assert!(updates.iter().all(UpdateState::is_move), "{updates:?}");
+            last_tick = tick;
+            moves += updates
+                .iter()
+                .filter(|update| matches!(update, UpdateState::MoveBall { .. }))
+                .count();
+        }
+    }
+}
+
+#[tokio::test]
+async fn batching_saves_bytes() {
+    const BALLS: usize = 4;
+    const MEASURE_FOR: Duration = Duration::from_secs(1);
+    let mut game = start_game(BALLS).await;
+    for (id, ball_write) in &mut game.balls {
+        shoot(*id, ball_write).await;
+    }
+    let observer = &mut game.observer;
+    // Skip ahead to when every ball is moving.
+    expect_message(
+        observer,
+        |m| matches!(m, ToClientMessage::Tick { updates, .. } if updates.len() == BALLS),
+    )
+    .await;
+
+    let (mut batched, mut per_update) = (0, 0);
+    let measure = async {
+        while let Some(message) = observer.next().await {
+            let message = message.unwrap();
+            let size = wire_size(&message);
+            batched += size;
+            let updates = match message {
+                ToClientMessage::Tick { tick, updates } => {
+                    per_update += wire_size(&PerUpdateMessage::Tick { tick });
+                    updates
+                }
+                ToClientMessage::Events { updates, .. } => updates,
+                _ => {
+                    per_update += size;
+                    continue;
+                }
+            };
+            for update in updates {
+                per_update += wire_size(&PerUpdateMessage::UpdateState(update));
+            }
+        }
+    };
+    _ = tokio::time::timeout(MEASURE_FOR, measure).await;
+    assert!(batched > 0);
+    assert!(
+        batched * 5 < per_update * 4,
+        "batching should save at least a fifth of the bytes: {batched} batched, {per_update} with a \
+         message per update"
+    );
+}
+
+#[tokio::test]
+async fn snapshots_build_on_acknowledged_ones() {
+    let mut game = start_game(2).await;
+    for (id, ball_write) in &mut game.balls {
+        shoot(*id, ball_write).await;
+    }
+    // The first snapshot is the whole state, since nothing was acknowledged yet.
+    let mut snapshots: Vec<(u64, GameState)> = vec![];
+    let mut state: Option<GameState> = None;
+    while snapshots.len() < 3 {
+        match game.observer.next().await.unwrap().unwrap() {
+            ToClientMessage::Tick { updates, .. } | ToClientMessage::Events { updates, .. } => {
+                if let Some(state) = &mut state {
+                    for update in &updates {
+                        update.apply(state);
+                    }
+                }
+            }
+            ToClientMessage::Snapshot {
+                tick,
+                baseline,
+                delta,
+                ..
+            } => {
+                let mut snapshot = match baseline {
+                    None => {
+                        assert!(snapshots.is_empty(), "acknowledged snapshot wasn't used");
+                        GameState::default()
+                    }
+                    Some(baseline) => {
+                        let (_, baseline) = snapshots
+                            .iter()
+                            .find(|(acked, _)| *acked == baseline)
+                            .expect("baseline was acknowledged");
+                        baseline.clone()
+                    }
+                };
+                delta.apply(&mut snapshot);
+                // The updates in between add up to the same state.
+                if let Some(state) = &state {
+                    assert_eq!(state, &snapshot);
+                }
+                state = Some(snapshot.clone());
+                snapshots.push((tick, snapshot));
+                write_message(
+                    &mut game.observer_write,
+                    &ToServerMessage::AckSnapshot { tick },
+                )
+                .await
+                .unwrap();
+            }
+            _ => {}
+        }
+    }
+}
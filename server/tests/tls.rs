@@ -0,0 +1,118 @@
+use std::{path::PathBuf, time::Duration};
+
+use common::{expect_message, start_server};
+use futures::StreamExt;
+use nope_the_hoop_proto::{
+    message::{ToClientMessage, ToServerMessage, CAPABILITIES, PROTOCOL_VERSION},
+    stream::{write_message, MessageStream},
+    tls::{client_config, CertificatePin, ServerTrust},
+};
+use tokio::{net::TcpStream, process::Child};
+use tokio_rustls::{
+    rustls::pki_types::{CertificateDer, ServerName},
+    TlsConnector,
+};
+
+mod common;
+
+/// A self-signed certificate for localhost, written out for the server to read.
+struct Certificate {
+    der: CertificateDer<'static>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl Certificate {
+    fn generate(name: &str) -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir();
+        let prefix = format!("nope-the-hoop-{}-{name}", std::process::id());
+        let cert_path = dir.join(format!("{prefix}-cert.pem"));
+        let key_path = dir.join(format!("{prefix}-key.pem"));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        Self {
+            der: certified.cert.der().clone(),
+            cert_path,
+            key_path,
+        }
+    }
+
+    async fn start_server(&self) -> (Child, u16) {
+        start_server(&[
+            "--tls-cert",
+            self.cert_path.to_str().unwrap(),
+            "--tls-key",
+            self.key_path.to_str().unwrap(),
+        ])
+        .await
+    }
+}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.cert_path);
+        _ = std::fs::remove_file(&self.key_path);
+    }
+}
+
+/// Connects over TLS and says hello, returning whether the server answered it.
+async fn say_hello(port: u16, trust: ServerTrust) -> bool {
+    let connector = TlsConnector::from(client_config(trust).unwrap());
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let Ok(stream) = connector.connect(server_name, stream).await else {
+        return false;
+    };
+    let (read, mut write) = tokio::io::split(stream);
+    let hello = ToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    write_message(&mut write, &hello).await.unwrap();
+    let mut read = MessageStream::<_, ToClientMessage>::new(read);
+    expect_message(&mut read, |m| {
+        matches!(m, ToClientMessage::HelloAccepted { .. })
+    })
+    .await;
+    true
+}
+
+#[tokio::test]
+async fn trusted_by_custom_ca() {
+    let certificate = Certificate::generate("ca");
+    let (_server, port) = certificate.start_server().await;
+    let trust = ServerTrust::CustomCa(vec![certificate.der.clone()]);
+    assert!(say_hello(port, trust).await);
+}
+
+#[tokio::test]
+async fn trusted_by_pin() {
+    let certificate = Certificate::generate("pin");
+    let (_server, port) = certificate.start_server().await;
+    let trust = ServerTrust::Pinned(CertificatePin::of(&certificate.der));
+    assert!(say_hello(port, trust).await);
+}
+
+#[tokio::test]
+async fn wrong_certificates_are_refused() {
+    let certificate = Certificate::generate("wrong");
+    let other = Certificate::generate("other");
+    let (_server, port) = certificate.start_server().await;
+    let pin = CertificatePin::of(&other.der);
+    assert!(!say_hello(port, ServerTrust::Pinned(pin)).await);
+    assert!(!say_hello(port, ServerTrust::CustomCa(vec![other.der.clone()])).await);
+    assert!(!say_hello(port, ServerTrust::PublicRoots).await);
+}
+
+#[tokio::test]
+async fn plain_tcp_is_refused() {
+    let certificate = Certificate::generate("plain");
+    let (_server, port) = certificate.start_server().await;
+    let (mut read, _write) = common::connect(port).await;
+    // The hello isn't a TLS record, so the server hangs up without answering it.
+    let answer = tokio::time::timeout(Duration::from_secs(5), read.next())
+        .await
+        .expect("Server should hang up");
+    assert!(!matches!(answer, Some(Ok(_))), "got {answer:?}");
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use common::{create_game, expect_message, join, join_as, start_udp_server};
+use futures::{Stream, StreamExt};
+use nope_the_hoop_proto::{
+    lobby::GameRef,
+    message::{PreferredRole, ToClientMessage, ToServerMessage, CAPABILITIES, PROTOCOL_VERSION},
+    state::{GameState, MatchPhase},
+    udp_stream::{connect, UdpWriter},
+};
+use tokio::net::UdpSocket;
+
+mod common;
+
+/// One datagram in this many is lost, each way.
+const LOSS: u64 = 4;
+const OBSERVE_FOR: Duration = Duration::from_secs(2);
+
+/// Relays datagrams between a client and the server, losing some the same way every run. The
+/// client is whoever sent to it last.
+async fn lossy_relay(server: SocketAddr) -> SocketAddr {
+    let client_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let server_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    server_side.connect(server).await.unwrap();
+    let relay_addr = client_side.local_addr().unwrap();
+    let (client_tx, mut client_rx) = tokio::sync::watch::channel(None);
+    let (from_client, to_server) = (client_side.clone(), server_side.clone());
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        let mut dice = 1;
+        loop {
+            let (len, addr) = from_client.recv_from(&mut buf).await.unwrap();
+            _ = client_tx.send(Some(addr));
+            if !lose(&mut dice) {
+                _ = to_server.send(&buf[..len]).await;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65536];
+        let mut dice = 2;
+        loop {
+            let len = server_side.recv(&mut buf).await.unwrap();
+            let client = *client_rx.borrow_and_update();
+            if let (Some(client), false) = (client, lose(&mut dice)) {
+                _ = client_side.send_to(&buf[..len], client).await;
+            }
+        }
+    });
+    relay_addr
+}
+
+fn lose(dice: &mut u64) -> bool {
+    *dice = dice.wrapping_mul(6364136223846793005).wrapping_add(1);
+    (*dice >> 33).is_multiple_of(LOSS)
+}
+
+/// Rebuilds each snapshot that arrives from the ones before it, acknowledging it if `ack` is
+/// given, and returns the states by tick once `OBSERVE_FOR` is up.
+async fn observe_snapshots(
+    read: &mut (impl Stream<Item = anyhow::Result<ToClientMessage>> + Unpin),
+    mut ack: impl FnMut(u64),
+) -> HashMap<u64, GameState> {
+    let mut snapshots = HashMap::new();
+    let observe = async {
+        while let Some(message) = read.next().await {
+            let ToClientMessage::Snapshot {
+                tick,
+                baseline,
+                delta,
+                ..
+            } = message.unwrap()
+            else {
+                continue;
+            };
+            let mut state = match baseline {
+                Some(baseline) => snapshots
+                    .get(&baseline)
+                    .cloned()
+                    .expect("baseline was acknowledged"),
+                None => GameState::default(),
+            };
+            delta.apply(&mut state);
+            snapshots.insert(tick, state);
+            ack(tick);
+        }
+    };
+    _ = tokio::time::timeout(OBSERVE_FOR, observe).await;
+    snapshots
+}
+
+async fn send(write: &mut UdpWriter<ToServerMessage>, message: ToServerMessage) {
+    write.write_messages(vec![message]).await.unwrap();
+}
+
+#[tokio::test]
+async fn udp_client_converges_despite_loss() {
+    let (_server, port, udp_port) = start_udp_server(&["--countdown-secs", "0"]).await;
+    let game = create_game(port, "udp").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+
+    let relay = lossy_relay(([127, 0, 0, 1], udp_port).into()).await;
+    let (mut ball, mut ball_write) = connect::<ToServerMessage, ToClientMessage>(relay)
+        .await
+        .unwrap();
+    let hello = ToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    send(&mut ball_write, hello).await;
+    let join_game = ToServerMessage::JoinGame {
+        game: GameRef::Id(game.id),
+        session: None,
+        password: None,
+        preferred_role: Some(PreferredRole::Ball),
+    };
+    send(&mut ball_write, join_game).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    let (mut observer, _observer_write) =
+        join_as(port, game.id, None, Some(PreferredRole::Observer)).await;
+    expect_message(&mut ball, |m| {
+        matches!(
+            m,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { .. },
+                ..
+            }
+        )
+    })
+    .await;
+    let shot = ToServerMessage::ShootBall {
+        id,
+        angle: 1.2,
+        seconds_pressed: 1.,
+    };
+    send(&mut ball_write, shot).await;
+
+    // Acknowledgements can be lost too, but the server only builds on ones that arrived.
+    let (acks_tx, mut acks_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(tick) = acks_rx.recv().await {
+            send(&mut ball_write, ToServerMessage::AckSnapshot { tick }).await;
+        }
+    });
+    let (over_udp, over_tcp) = tokio::join!(
+        observe_snapshots(&mut ball, |tick| _ = acks_tx.send(tick)),
+        observe_snapshots(&mut observer, |_| {}),
+    );
+
+    let mut common_ticks: Vec<_> = over_udp
+        .keys()
+        .filter(|tick| over_tcp.contains_key(tick))
+        .collect();
+    common_ticks.sort();
+    assert!(
+        common_ticks.len() >= 3,
+        "only {} snapshots made it over UDP",
+        over_udp.len()
+    );
+    for &tick in &common_ticks {
+        assert_eq!(
+            over_udp[tick], over_tcp[tick],
+            "states differ at tick {tick}"
+        );
+    }
+    // The shot went through, so there was something to converge on.
+    assert!(common_ticks
+        .windows(2)
+        .any(|ticks| over_udp[ticks[0]].ball_positions[&id]
+            != over_udp[ticks[1]].ball_positions[&id]));
+}
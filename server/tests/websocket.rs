@@ -0,0 +1,113 @@
+use common::{create_game, expect_message, expect_update, join, start_ws_server};
+use futures::{stream::SplitSink, SinkExt, Stream, StreamExt};
+use nope_the_hoop_proto::{
+    lobby::GameRef,
+    message::{PreferredRole, ToClientMessage, ToServerMessage, CAPABILITIES, PROTOCOL_VERSION},
+    state::{MatchPhase, UpdateState},
+};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+mod common;
+
+type WebSocketWrite = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+/// Connects over ws://127.0.0.1 and says hello, leaving the client in the lobby.
+async fn ws_connect(
+    port: u16,
+) -> (
+    impl Stream<Item = anyhow::Result<ToClientMessage>> + Unpin,
+    WebSocketWrite,
+) {
+    let (websocket, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{port}"))
+        .await
+        .unwrap();
+    let (mut write, read) = websocket.split();
+    // The server hanging up without a close frame ends the stream the same as with one.
+    let read = read.filter_map(|message| async move {
+        let Ok(Message::Binary(buf)) = message else {
+            return None;
+        };
+        Some(Ok(ciborium::from_reader(&buf[..]).unwrap()))
+    });
+    let hello = ToServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    ws_send(&mut write, &hello).await;
+    (Box::pin(read), write)
+}
+
+async fn ws_send(write: &mut WebSocketWrite, message: &ToServerMessage) {
+    let mut buf = vec![];
+    ciborium::into_writer(message, &mut buf).unwrap();
+    write.send(Message::Binary(buf)).await.unwrap();
+}
+
+#[tokio::test]
+async fn websocket_and_tcp_players_share_a_game() {
+    let (_server, port, ws_port) = start_ws_server(&["--countdown-secs", "0"]).await;
+    let game = create_game(port, "websocket").await;
+    let (mut hoop, _hoop_write) = join(port, game.id, None).await;
+    expect_message(&mut hoop, |m| {
+        matches!(m, ToClientMessage::EstablishAsHoop { .. })
+    })
+    .await;
+
+    let (mut ball, mut ball_write) = ws_connect(ws_port).await;
+    expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::HelloAccepted { .. })
+    })
+    .await;
+    let join_game = ToServerMessage::JoinGame {
+        game: GameRef::Id(game.id),
+        session: None,
+        password: None,
+        preferred_role: Some(PreferredRole::Ball),
+    };
+    ws_send(&mut ball_write, &join_game).await;
+    let ToClientMessage::EstablishAsBall { id, .. } = expect_message(&mut ball, |m| {
+        matches!(m, ToClientMessage::EstablishAsBall { .. })
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    expect_message(&mut ball, |m| {
+        matches!(
+            m,
+            ToClientMessage::PhaseChanged {
+                phase: MatchPhase::Playing { .. },
+                ..
+            }
+        )
+    })
+    .await;
+    let shot = ToServerMessage::ShootBall {
+        id,
+        angle: 1.2,
+        seconds_pressed: 1.,
+    };
+    ws_send(&mut ball_write, &shot).await;
+
+    // Both see the shot, whichever way they're connected.
+    let moved =
+        |u: &UpdateState| matches!(u, UpdateState::MoveBall { id: moved, .. } if *moved == id);
+    expect_update(&mut hoop, moved).await;
+    expect_update(&mut ball, moved).await;
+}
+
+#[tokio::test]
+async fn text_messages_are_refused() {
+    let (_server, _port, ws_port) = start_ws_server(&[]).await;
+    let (mut read, mut write) = ws_connect(ws_port).await;
+    expect_message(&mut read, |m| {
+        matches!(m, ToClientMessage::HelloAccepted { .. })
+    })
+    .await;
+    write
+        .send(Message::Text("ListGames".to_owned()))
+        .await
+        .unwrap();
+    assert!(read.next().await.is_none(), "connection should be closed");
+}
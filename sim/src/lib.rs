@@ -0,0 +1,517 @@
+//! The game's physics, shared by the server that runs it and the client that predicts it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use nope_the_hoop_proto::{
+    message::HorizontalDirection,
+    state::{GameState, Point, UpdateState},
+};
+
+/// The simulation moves in fixed steps, however often the game loop gets to run them, so the same
+/// inputs on the same ticks always play out the same. Only operations IEEE 754 rounds exactly are
+/// used, plus trigonometry from `libm` rather than the platform's, so that holds on any machine.
+pub const TICK_RATE: u32 = 60;
+pub const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_RATE as u64);
+pub const TICK_SECONDS: f32 = 1. / TICK_RATE as f32;
+pub const INITIAL_HOOP_X: f32 = 100.;
+pub const HOOP_MIN_X: f32 = 0.;
+pub const HOOP_MAX_X: f32 = 200.;
+pub const HOOP_Y: f32 = 0.;
+pub const HOOP_WIDTH: f32 = 50.;
+pub const HOOP_HEIGHT: f32 = 10.;
+pub const HOOP_SPEED: f32 = 100.;
+/// Balls spawn in a row going left from here, each in the first free spot.
+pub const FIRST_BALL_POSITION: Point = Point { x: -100., y: 10. };
+pub const BALL_SPAWN_SPACING: f32 = 40.;
+pub const BALL_RADIUS: f32 = 10.;
+pub const BALL_SPEED_PER_SECOND_PRESSED: f32 = 100.;
+pub const BALL_MAX_SPEED: f32 = 100.;
+pub const GRAVITY: f32 = 9.81;
+/// The most hoop movement, in seconds of key presses, that can be banked while no input arrives.
+/// Inputs are allowed to bunch up this much (e.g. from network jitter) before they get clamped.
+pub const MAX_HOOP_INPUT_BURST: f32 = 0.25;
+/// Balls that fall below the floor or leave the sides of the court are reset to their spawn point.
+pub const FLOOR_Y: f32 = -300.;
+pub const COURT_MIN_X: f32 = -600.;
+pub const COURT_MAX_X: f32 = 600.;
+
+/// Input that a fair client couldn't have sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputViolation {
+    /// A NaN, infinite or negative number. The input is ignored.
+    InvalidNumber,
+    /// More key press time than has actually passed. The input is clamped to what has.
+    OverBudget,
+    /// Shooting a ball that's already in flight. The input is ignored.
+    BallInFlight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShotOutcome {
+    /// The ball dropped cleanly through the hoop's opening.
+    Score,
+    /// The ball hit the rim or came up against the hoop from below.
+    Nope,
+}
+
+/// The server-only part of a ball's state.
+struct BallPhysics {
+    spawn: Point,
+    /// `None` while the ball is waiting to be shot.
+    velocity: Option<Point>,
+    /// Whether the current shot already scored or was noped.
+    shot_resolved: bool,
+}
+
+impl BallPhysics {
+    fn new(spawn: Point) -> Self {
+        Self {
+            spawn,
+            velocity: None,
+            shot_resolved: false,
+        }
+    }
+}
+
+pub struct Game {
+    state: GameState,
+    /// Kept in id order, so that balls are always stepped (and their outcomes reported) in the
+    /// same order.
+    balls: BTreeMap<u32, BallPhysics>,
+    /// How many ticks the game has run.
+    tick: u64,
+    next_ball_id: u32,
+    /// Seconds of hoop movement the hoop can still make, replenished in real time.
+    hoop_input_budget: f32,
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Self {
+            state: GameState {
+                hoop_x: INITIAL_HOOP_X,
+                ball_positions: HashMap::new(),
+                scores: HashMap::new(),
+                hoop_score: 0,
+                nopes: 0,
+            },
+            balls: BTreeMap::new(),
+            tick: 0,
+            next_ball_id: 0,
+            hoop_input_budget: MAX_HOOP_INPUT_BURST,
+        }
+    }
+}
+
+impl Game {
+    pub fn state(&self) -> &GameState {
+        &self.state
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn balls_in_flight(&self) -> bool {
+        self.balls.values().any(|ball| ball.velocity.is_some())
+    }
+
+    /// Starts everyone's score over, returning the update announcing it.
+    pub fn reset_scores(&mut self) -> UpdateState {
+        let update = UpdateState::ScoresReset;
+        update.apply(&mut self.state);
+        update
+    }
+
+    /// Trades the score of a ball's player, who's taking the hoop, for that of the hoop's, who's
+    /// taking the ball.
+    pub fn swap_scores(&mut self, ball_id: u32) -> UpdateState {
+        let update = UpdateState::ScoresSwapped { id: ball_id };
+        update.apply(&mut self.state);
+        update
+    }
+
+    /// Forgets what the hoop's player scored, as they left.
+    pub fn clear_hoop_score(&mut self) -> Option<UpdateState> {
+        if self.state.hoop_score == 0 {
+            return None;
+        }
+        let update = UpdateState::HoopScoreCleared;
+        update.apply(&mut self.state);
+        Some(update)
+    }
+
+    /// Adds a ball at the first free spawn spot, returning its id and the update announcing it.
+    pub fn add_ball(&mut self) -> (u32, UpdateState) {
+        let id = self.next_ball_id;
+        self.next_ball_id += 1;
+        let position = (0..)
+            .map(|slot| Point {
+                x: FIRST_BALL_POSITION.x - BALL_SPAWN_SPACING * slot as f32,
+                y: FIRST_BALL_POSITION.y,
+            })
+            .find(|spawn| !self.balls.values().any(|ball| ball.spawn == *spawn))
+            .expect("There's always a free spawn spot");
+        self.balls.insert(id, BallPhysics::new(position));
+        let update = UpdateState::AddBall { id, position };
+        update.apply(&mut self.state);
+        (id, update)
+    }
+
+    pub fn remove_ball(&mut self, id: u32) -> Option<UpdateState> {
+        self.balls.remove(&id)?;
+        let update = UpdateState::RemoveBall { id };
+        update.apply(&mut self.state);
+        Some(update)
+    }
+
+    /// Moves the hoop. An over-budget move is still made, as far as the budget allows.
+    pub fn move_hoop(
+        &mut self,
+        direction: HorizontalDirection,
+        seconds_pressed: f32,
+    ) -> Result<(), InputViolation> {
+        if !seconds_pressed.is_finite() || seconds_pressed < 0. {
+            return Err(InputViolation::InvalidNumber);
+        }
+        let allowed_seconds = seconds_pressed.min(self.hoop_input_budget);
+        self.hoop_input_budget -= allowed_seconds;
+        self.state.hoop_x = moved_hoop_x(self.state.hoop_x, direction, allowed_seconds);
+        if allowed_seconds < seconds_pressed {
+            return Err(InputViolation::OverBudget);
+        }
+        Ok(())
+    }
+
+    pub fn shoot_ball(
+        &mut self,
+        id: u32,
+        angle: f32,
+        seconds_pressed: f32,
+    ) -> Result<(), InputViolation> {
+        let Some(ball) = self.balls.get_mut(&id) else {
+            return Ok(());
+        };
+        if !angle.is_finite() || !seconds_pressed.is_finite() || seconds_pressed < 0. {
+            return Err(InputViolation::InvalidNumber);
+        }
+        if ball.velocity.is_some() {
+            return Err(InputViolation::BallInFlight);
+        }
+        ball.velocity = Some(calculate_ball_velocity(angle, seconds_pressed));
+        ball.shot_resolved = false;
+        Ok(())
+    }
+
+    /// Runs the simulation for one tick.
+    pub fn step(&mut self, updates: &mut Vec<UpdateState>) {
+        self.tick += 1;
+        self.hoop_input_budget = (self.hoop_input_budget + TICK_SECONDS).min(MAX_HOOP_INPUT_BURST);
+        let mut outcomes = vec![];
+        for (id, physics) in self.balls.iter_mut() {
+            let Some(velocity) = &mut physics.velocity else {
+                continue;
+            };
+            let Some(ball) = self.state.ball_positions.get_mut(id) else {
+                continue;
+            };
+            if out_of_bounds(*ball) {
+                physics.velocity = None;
+                physics.shot_resolved = false;
+                *ball = physics.spawn;
+                updates.push(UpdateState::ResetBall {
+                    id: *id,
+                    position: *ball,
+                });
+                continue;
+            }
+            // Constant acceleration integrates exactly, so the path doesn't depend on the tick rate.
+            let previous = *ball;
+            ball.x += velocity.x * TICK_SECONDS;
+            ball.y += velocity.y * TICK_SECONDS - 0.5 * GRAVITY * TICK_SECONDS * TICK_SECONDS;
+            velocity.y -= GRAVITY * TICK_SECONDS;
+            updates.push(UpdateState::MoveBall {
+                id: *id,
+                position: *ball,
+            });
+            if physics.shot_resolved {
+                continue;
+            }
+            if let Some(outcome) = check_hoop(self.state.hoop_x, previous, *ball) {
+                physics.shot_resolved = true;
+                outcomes.push((*id, outcome));
+            }
+        }
+        for (id, outcome) in outcomes {
+            let update = match outcome {
+                ShotOutcome::Score => {
+                    let score = self.state.scores.get(&id).copied().unwrap_or(0) + 1;
+                    UpdateState::Scored { id, score }
+                }
+                ShotOutcome::Nope => UpdateState::Noped {
+                    id,
+                    nopes: self.state.nopes + 1,
+                },
+            };
+            update.apply(&mut self.state);
+            updates.push(update);
+        }
+    }
+}
+
+/// Where the hoop ends up after a move, without the input budget. This is all a client needs to
+/// predict its own hoop.
+pub fn moved_hoop_x(hoop_x: f32, direction: HorizontalDirection, seconds_pressed: f32) -> f32 {
+    let sign = match direction {
+        HorizontalDirection::Left => -1.,
+        HorizontalDirection::Right => 1.,
+    };
+    (hoop_x + sign * HOOP_SPEED * seconds_pressed).clamp(HOOP_MIN_X, HOOP_MAX_X)
+}
+
+fn calculate_ball_velocity(angle: f32, seconds_pressed: f32) -> Point {
+    let speed = (seconds_pressed * BALL_SPEED_PER_SECOND_PRESSED).clamp(0., BALL_MAX_SPEED);
+    let x = libm::cosf(angle) * speed;
+    let y = libm::sinf(angle) * speed;
+    Point { x, y }
+}
+
+fn out_of_bounds(ball: Point) -> bool {
+    ball.y < FLOOR_Y || ball.x < COURT_MIN_X || ball.x > COURT_MAX_X
+}
+
+/// Checks whether a ball moving from `from` to `to` in one step hit the hoop. The whole path is
+/// tested rather than just its end, so a fast ball can't skip over the hoop between frames.
+fn check_hoop(hoop_x: f32, from: Point, to: Point) -> Option<ShotOutcome> {
+    let half_width = HOOP_WIDTH / 2.;
+    // The rim is the two ends of the hoop, each as thick as the hoop itself.
+    let rim_reach = BALL_RADIUS + HOOP_HEIGHT / 2.;
+    for rim_x in [hoop_x - half_width, hoop_x + half_width] {
+        let rim = Point {
+            x: rim_x,
+            y: HOOP_Y,
+        };
+        if distance_to_segment(rim, from, to) < rim_reach {
+            return Some(ShotOutcome::Nope);
+        }
+    }
+    let crossed_down = from.y > HOOP_Y && to.y <= HOOP_Y;
+    let crossed_up = from.y < HOOP_Y && to.y >= HOOP_Y;
+    if !crossed_down && !crossed_up {
+        return None;
+    }
+    let t = (from.y - HOOP_Y) / (from.y - to.y);
+    let crossing_x = from.x + (to.x - from.x) * t;
+    if (crossing_x - hoop_x).abs() >= half_width {
+        return None;
+    }
+    if crossed_down {
+        Some(ShotOutcome::Score)
+    } else {
+        Some(ShotOutcome::Nope)
+    }
+}
+
+fn distance_to_segment(point: Point, start: Point, end: Point) -> f32 {
+    let (dx, dy) = (end.x - start.x, end.y - start.y);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared == 0. {
+        0.
+    } else {
+        (((point.x - start.x) * dx + (point.y - start.y) * dy) / length_squared).clamp(0., 1.)
+    };
+    let (closest_x, closest_y) = (start.x + dx * t, start.y + dy * t);
+    let (dx, dy) = (point.x - closest_x, point.y - closest_y);
+    (dx * dx + dy * dy).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ball_spawns() {
+        let mut game = Game::default();
+        let (first, _) = game.add_ball();
+        let (second, _) = game.add_ball();
+        assert_ne!(first, second);
+        let first_spawn = game.state().ball_positions[&first];
+        assert_ne!(first_spawn, game.state().ball_positions[&second]);
+        assert!(game.remove_ball(first).is_some());
+        assert!(game.remove_ball(first).is_none());
+        // The freed spot is reused, but not the id.
+        let (third, _) = game.add_ball();
+        assert_ne!(third, first);
+        assert_eq!(game.state().ball_positions[&third], first_spawn);
+    }
+
+    #[test]
+    fn hoop_input_budget() {
+        let mut game = Game::default();
+        assert_eq!(
+            game.move_hoop(HorizontalDirection::Right, 1000.),
+            Err(InputViolation::OverBudget)
+        );
+        assert_eq!(
+            game.state().hoop_x,
+            INITIAL_HOOP_X + HOOP_SPEED * MAX_HOOP_INPUT_BURST
+        );
+        assert_eq!(
+            game.move_hoop(HorizontalDirection::Left, f32::NAN),
+            Err(InputViolation::InvalidNumber)
+        );
+        for _ in 0..TICK_RATE / 10 {
+            game.step(&mut vec![]);
+        }
+        assert_eq!(game.move_hoop(HorizontalDirection::Left, 0.05), Ok(()));
+    }
+
+    #[test]
+    fn hoop_clamps() {
+        let mut game = Game::default();
+        for (direction, limit) in [
+            (HorizontalDirection::Left, HOOP_MIN_X),
+            (HorizontalDirection::Right, HOOP_MAX_X),
+        ] {
+            // Far enough to cross the whole range, in moves the budget allows.
+            for _ in 0..20 {
+                let _ = game.move_hoop(direction, MAX_HOOP_INPUT_BURST);
+                for _ in 0..TICK_RATE / 4 {
+                    game.step(&mut vec![]);
+                }
+            }
+            assert_eq!(game.state().hoop_x, limit);
+        }
+    }
+
+    #[test]
+    fn trajectory() {
+        let mut game = Game::default();
+        let (id, _) = game.add_ball();
+        let angle = 0.5;
+        game.shoot_ball(id, angle, 1.).unwrap();
+        let (vx, vy) = (
+            libm::cosf(angle) * BALL_MAX_SPEED,
+            libm::sinf(angle) * BALL_MAX_SPEED,
+        );
+        for tick in 1..=TICK_RATE {
+            let mut updates = vec![];
+            game.step(&mut updates);
+            let t = tick as f32 * TICK_SECONDS;
+            let expected = Point {
+                x: FIRST_BALL_POSITION.x + vx * t,
+                y: FIRST_BALL_POSITION.y + vy * t - 0.5 * GRAVITY * t * t,
+            };
+            let position = game.state().ball_positions[&id];
+            assert!(
+                (position.x - expected.x).abs() < 1e-3 && (position.y - expected.y).abs() < 1e-3,
+                "tick {tick}: {position:?} should be {expected:?}"
+            );
+            assert_eq!(updates, [UpdateState::MoveBall { id, position }]);
+        }
+        assert!(game.balls_in_flight());
+    }
+
+    /// Plays out a fixed sequence of inputs, returning every update it made.
+    fn replay() -> (Game, Vec<UpdateState>) {
+        let mut game = Game::default();
+        let (first, _) = game.add_ball();
+        let (second, _) = game.add_ball();
+        let mut updates = vec![];
+        for tick in 0..TICK_RATE * 10 {
+            match tick {
+                // Low and flat, into the hoop's rim.
+                10 => game.shoot_ball(first, 0.048, 1.).unwrap(),
+                25 => game.shoot_ball(second, 0.9, 1.).unwrap(),
+                _ => {}
+            }
+            if tick >= 180 && tick % 3 == 0 {
+                let direction = if tick < 300 {
+                    HorizontalDirection::Left
+                } else {
+                    HorizontalDirection::Right
+                };
+                game.move_hoop(direction, TICK_SECONDS * 2.).unwrap();
+            }
+            game.step(&mut updates);
+        }
+        (game, updates)
+    }
+
+    #[test]
+    fn replays_match() {
+        let (game, updates) = replay();
+        let (replayed_game, replayed_updates) = replay();
+        assert_eq!(game.tick(), u64::from(TICK_RATE) * 10);
+        assert_eq!(updates, replayed_updates);
+        assert_eq!(game.state(), replayed_game.state());
+    }
+
+    /// Pins down exactly where the replay ends up, on any machine, so any change to the physics
+    /// shows up here.
+    #[test]
+    fn replay_golden() {
+        let (game, updates) = replay();
+        let state = game.state();
+        let bits = |id| {
+            let position = state.ball_positions[&id];
+            (position.x.to_bits(), position.y.to_bits())
+        };
+        // The first ball was noped, fell off the court and went back to its spawn.
+        assert_eq!(bits(0), ((-100f32).to_bits(), 10f32.to_bits()));
+        assert_eq!(bits(1), (0x43e3da9e, 0x439b1b08));
+        assert_eq!(state.hoop_x, HOOP_MAX_X);
+        assert_eq!(state.nopes, 1);
+        assert!(state.scores.is_empty());
+        assert!(updates.contains(&UpdateState::ResetBall {
+            id: 0,
+            position: FIRST_BALL_POSITION
+        }));
+    }
+
+    #[test]
+    fn invalid_shots() {
+        let mut game = Game::default();
+        let (id, _) = game.add_ball();
+        assert_eq!(
+            game.shoot_ball(id, f32::INFINITY, 0.5),
+            Err(InputViolation::InvalidNumber)
+        );
+        assert_eq!(game.shoot_ball(id, 1., 0.5), Ok(()));
+        assert_eq!(
+            game.shoot_ball(id, 1., 0.5),
+            Err(InputViolation::BallInFlight)
+        );
+    }
+
+    #[test]
+    fn hoop_outcomes() {
+        let point = |x, y| Point { x, y };
+        // Straight down the middle.
+        assert_eq!(
+            check_hoop(100., point(100., 5.), point(100., -5.)),
+            Some(ShotOutcome::Score)
+        );
+        // So fast that neither end of the step is near the hoop.
+        assert_eq!(
+            check_hoop(100., point(90., 500.), point(110., -500.)),
+            Some(ShotOutcome::Score)
+        );
+        // Clipping the rim.
+        assert_eq!(
+            check_hoop(100., point(120., 5.), point(120., -5.)),
+            Some(ShotOutcome::Nope)
+        );
+        // From below.
+        assert_eq!(
+            check_hoop(100., point(100., -5.), point(100., 5.)),
+            Some(ShotOutcome::Nope)
+        );
+        // Nowhere near.
+        assert_eq!(check_hoop(100., point(0., 5.), point(0., -5.)), None);
+        assert_eq!(check_hoop(100., point(100., 50.), point(100., 40.)), None);
+    }
+}